@@ -1,2 +1,2 @@
 -- Add down migration script here
-drop table if existS posts;
\ No newline at end of file
+DROP TABLE IF EXISTS post;
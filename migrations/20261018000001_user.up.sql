@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS "user" (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    email varchar(255) not null,
+    password text not null,
+    created_at TIMESTAMP not null default NOW(),
+    updated_at TIMESTAMP not null default NOW(),
+    CONSTRAINT user_email_key UNIQUE (email)
+);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE "user" DROP COLUMN IF EXISTS role;
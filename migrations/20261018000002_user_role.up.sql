@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS role varchar(50) not null default 'user';
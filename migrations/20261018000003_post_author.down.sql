@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS post_author_id_idx;
+ALTER TABLE post DROP COLUMN IF EXISTS author_id;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE post ADD COLUMN IF NOT EXISTS author_id uuid REFERENCES "user"(id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS post_author_id_idx ON post(author_id);
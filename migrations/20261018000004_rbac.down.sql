@@ -0,0 +1,11 @@
+-- Add down migration script here
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS role varchar(50) not null default 'user';
+
+UPDATE "user" u SET role = 'admin'
+FROM user_roles ur JOIN roles r ON r.id = ur.role_id
+WHERE ur.user_id = u.id AND r.name = 'admin';
+
+DROP TABLE IF EXISTS user_roles;
+DROP TABLE IF EXISTS role_permissions;
+DROP TABLE IF EXISTS permissions;
+DROP TABLE IF EXISTS roles;
@@ -0,0 +1,45 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS roles (
+    id serial PRIMARY KEY,
+    name varchar(50) not null UNIQUE,
+    created_at TIMESTAMP not null default NOW()
+);
+
+CREATE TABLE IF NOT EXISTS permissions (
+    id serial PRIMARY KEY,
+    name varchar(100) not null UNIQUE,
+    created_at TIMESTAMP not null default NOW()
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role_id integer not null REFERENCES roles(id) ON DELETE CASCADE,
+    permission_id integer not null REFERENCES permissions(id) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, permission_id)
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id uuid not null REFERENCES "user"(id) ON DELETE CASCADE,
+    role_id integer not null REFERENCES roles(id) ON DELETE CASCADE,
+    created_at TIMESTAMP not null default NOW(),
+    PRIMARY KEY (user_id, role_id)
+);
+
+INSERT INTO roles (name) VALUES ('user'), ('admin') ON CONFLICT (name) DO NOTHING;
+
+-- post:moderate lets a user change posts written by someone else
+INSERT INTO permissions (name) VALUES
+    ('post:create'), ('post:update'), ('post:delete'), ('post:moderate')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE (r.name = 'user' AND p.name IN ('post:create', 'post:update', 'post:delete'))
+   OR r.name = 'admin'
+ON CONFLICT DO NOTHING;
+
+-- carry over the single role column added with post ownership
+INSERT INTO user_roles (user_id, role_id)
+SELECT u.id, r.id FROM "user" u JOIN roles r ON r.name = u.role
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE "user" DROP COLUMN IF EXISTS role;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS user_set_updated_at ON "user";
+DROP TRIGGER IF EXISTS post_set_updated_at ON post;
+DROP FUNCTION IF EXISTS set_updated_at();
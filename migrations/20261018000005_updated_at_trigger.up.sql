@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE OR REPLACE FUNCTION set_updated_at() RETURNS trigger AS $$
+BEGIN
+    NEW.updated_at = NOW();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS post_set_updated_at ON post;
+CREATE TRIGGER post_set_updated_at
+    BEFORE UPDATE ON post
+    FOR EACH ROW EXECUTE FUNCTION set_updated_at();
+
+DROP TRIGGER IF EXISTS user_set_updated_at ON "user";
+CREATE TRIGGER user_set_updated_at
+    BEFORE UPDATE ON "user"
+    FOR EACH ROW EXECUTE FUNCTION set_updated_at();
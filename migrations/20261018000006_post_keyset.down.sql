@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS post_create_at_id_idx;
+ALTER TABLE post ALTER COLUMN create_at DROP NOT NULL;
@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- keyset pagination walks (create_at, id), which needs create_at to be set
+UPDATE post SET create_at = NOW() WHERE create_at IS NULL;
+ALTER TABLE post ALTER COLUMN create_at SET NOT NULL;
+CREATE INDEX IF NOT EXISTS post_create_at_id_idx ON post(create_at DESC, id DESC);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS post_search_idx;
+ALTER TABLE post DROP COLUMN IF EXISTS search;
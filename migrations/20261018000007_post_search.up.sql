@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE post ADD COLUMN IF NOT EXISTS search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', title::text), 'A') ||
+    setweight(to_tsvector('english', content), 'B')
+) STORED;
+CREATE INDEX IF NOT EXISTS post_search_idx ON post USING GIN(search);
@@ -0,0 +1,17 @@
+-- Add up migration script here
+-- events are written in the same transaction as the change they describe
+-- and published to the broker by the outbox relay afterwards
+CREATE TABLE IF NOT EXISTS outbox (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    event_type varchar(100) not null,
+    aggregate_type varchar(50) not null,
+    aggregate_id varchar(64) not null,
+    payload jsonb not null,
+    created_at TIMESTAMP not null default NOW(),
+    published_at TIMESTAMP,
+    attempts integer not null default 0,
+    last_error text,
+    next_attempt_at TIMESTAMP not null default NOW()
+);
+
+CREATE INDEX IF NOT EXISTS outbox_pending_idx ON outbox(next_attempt_at) WHERE published_at IS NULL;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP MATERIALIZED VIEW IF EXISTS post_stats;
+DROP TABLE IF EXISTS job_runs;
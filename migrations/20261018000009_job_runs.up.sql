@@ -0,0 +1,22 @@
+-- Add up migration script here
+-- one row per scheduled run, whichever replica took it
+CREATE TABLE IF NOT EXISTS job_runs (
+    id bigserial PRIMARY KEY,
+    job varchar(100) not null,
+    scheduled_at TIMESTAMP not null,
+    started_at TIMESTAMP not null default NOW(),
+    finished_at TIMESTAMP,
+    status varchar(20) not null default 'running',
+    duration_ms bigint,
+    detail text,
+    UNIQUE (job, scheduled_at)
+);
+
+CREATE MATERIALIZED VIEW IF NOT EXISTS post_stats AS
+SELECT author_id, COUNT(*) AS posts, MAX(create_at) AS last_post_at
+FROM post
+WHERE author_id IS NOT NULL
+GROUP BY author_id;
+
+-- needed to refresh concurrently, without blocking readers
+CREATE UNIQUE INDEX IF NOT EXISTS post_stats_author_idx ON post_stats(author_id);
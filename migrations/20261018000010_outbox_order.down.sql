@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS outbox_aggregate_pending_idx;
+ALTER TABLE outbox DROP COLUMN IF EXISTS seq;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- taken at insert, after the write locked its row, so it follows the commit
+-- order of writes to one aggregate; created_at is the transaction start
+ALTER TABLE outbox ADD COLUMN IF NOT EXISTS seq bigserial;
+
+CREATE INDEX IF NOT EXISTS outbox_aggregate_pending_idx
+    ON outbox(aggregate_type, aggregate_id, seq) WHERE published_at IS NULL;
@@ -0,0 +1,286 @@
+use std::time::Duration;
+
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use validator::Validate;
+
+use crate::config::settings::Settings;
+use crate::modules::auth::{auth_handler::hash_password, auth_models::Register};
+use crate::service::health::{HealthRegistry, PostgresCheck, RabbitMqCheck, RedisCheck};
+use crate::service::outbox::{self, USER_REGISTERED};
+use crate::service::{migrations, rabbitmq::rabbit_connect, redis::redis_connect};
+use crate::utils::api_error::validation_details;
+use crate::utils::jwt::JwtKeys;
+
+pub const USAGE: &str = "usage:
+    actix_starter [serve] [--migrate]                start the server, optionally migrating first
+    actix_starter migrate up                         apply pending migrations
+    actix_starter migrate down [--target VERSION]    revert the latest migration, or down to VERSION
+    actix_starter migrate status                     list migrations and whether they are applied
+    actix_starter create-admin --email EMAIL         create an admin, password from ADMIN_PASSWORD or the terminal
+        [--reset-existing]                           make an existing account admin and reset its password
+    actix_starter check-config                       validate configuration and reach every dependency";
+
+/// How long `check-config` waits for each dependency.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve { migrate: bool },
+    Migrate(MigrateCommand),
+    CreateAdmin { email: String, reset_existing: bool },
+    CheckConfig,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MigrateCommand {
+    Up,
+    Down { target: Option<i64> },
+    Status,
+}
+
+/// Parses the arguments after the binary name.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] | ["serve"] => Ok(Command::Serve { migrate: false }),
+        ["--migrate"] | ["serve", "--migrate"] => Ok(Command::Serve { migrate: true }),
+        ["migrate", "up"] => Ok(Command::Migrate(MigrateCommand::Up)),
+        ["migrate", "down"] => Ok(Command::Migrate(MigrateCommand::Down { target: None })),
+        ["migrate", "down", "--target", target] => {
+            let target = target.parse().map_err(|_| format!("invalid migration version {}", target))?;
+            Ok(Command::Migrate(MigrateCommand::Down { target: Some(target) }))
+        }
+        ["migrate", "status"] => Ok(Command::Migrate(MigrateCommand::Status)),
+        ["create-admin", "--email", email] => Ok(Command::CreateAdmin { email: email.to_string(), reset_existing: false }),
+        ["create-admin", "--email", email, "--reset-existing"] | ["create-admin", "--reset-existing", "--email", email] => {
+            Ok(Command::CreateAdmin { email: email.to_string(), reset_existing: true })
+        }
+        ["check-config"] => Ok(Command::CheckConfig),
+        _ => Err(format!("unknown command: {}", args.join(" "))),
+    }
+}
+
+/// Runs a `migrate` subcommand and returns the process exit code.
+pub async fn migrate(pool: &PgPool, command: MigrateCommand) -> i32 {
+    let result = match command {
+        MigrateCommand::Up => migrations::up(pool).await.map(|_| println!("✅ migrations applied")),
+        MigrateCommand::Down { target } => migrations::down(pool, target)
+            .await
+            .map(|version| println!("✅ reverted to version {}", version)),
+        MigrateCommand::Status => migrations::status(pool).await.map(|migrations| {
+            for migration in migrations {
+                let state = match (migration.applied, migration.modified) {
+                    (true, true) => "applied (modified since)",
+                    (true, false) => "applied",
+                    (false, _) => "pending",
+                };
+                println!("{:<16} {:<28} {}", migration.version, migration.description, state);
+            }
+        }),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            println!("migration failed: {}", err);
+            1
+        }
+    }
+}
+
+/// Creates `email` as an admin. An existing account is left alone and the
+/// command fails, unless `reset_existing` is set: then the account gets the
+/// admin role and the given password, so whoever registered it loses it.
+pub async fn create_admin(pool: &PgPool, email: &str, reset_existing: bool) -> i32 {
+    let password = match std::env::var("ADMIN_PASSWORD") {
+        Ok(password) => password,
+        // read from the terminal with echo off
+        Err(_) => match rpassword::prompt_password("password: ") {
+            Ok(password) => password,
+            Err(err) => {
+                println!("failed to read password, set ADMIN_PASSWORD instead: {}", err);
+                return 1;
+            }
+        },
+    };
+
+    let admin = Register { email: email.to_string(), password };
+    if let Err(errors) = admin.validate() {
+        println!("invalid admin account: {}", validation_details(&errors));
+        return 1;
+    }
+
+    match insert_admin(pool, admin, reset_existing).await {
+        Ok(AdminOutcome::Created) => {
+            println!("✅ admin {} created", email);
+            0
+        }
+        Ok(AdminOutcome::Reset) => {
+            println!("✅ {} already existed, admin role granted and password reset", email);
+            0
+        }
+        Ok(AdminOutcome::Exists) => {
+            println!("❌ {} already exists, pass --reset-existing to make it admin with the given password", email);
+            1
+        }
+        Err(err) => {
+            println!("failed to create admin: {}", err);
+            1
+        }
+    }
+}
+
+enum AdminOutcome {
+    Created,
+    /// Existed, nothing was changed.
+    Exists,
+    /// Existed, now admin with the new password.
+    Reset,
+}
+
+async fn insert_admin(pool: &PgPool, admin: Register, reset_existing: bool) -> Result<AdminOutcome, String> {
+    let password_hash = hash_password(&admin.password).map_err(|e| e.to_string())?;
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    let created = sqlx::query_scalar!(
+        r#"INSERT INTO "user" (email, password) VALUES ($1, $2)
+        ON CONFLICT (email) DO NOTHING RETURNING id"#,
+        admin.email,
+        password_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if created.is_none() {
+        if !reset_existing {
+            return Ok(AdminOutcome::Exists);
+        }
+        sqlx::query!(r#"UPDATE "user" SET password = $2 WHERE email = $1"#, admin.email, password_hash)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    // admins keep the regular user role too
+    sqlx::query!(
+        r#"INSERT INTO user_roles (user_id, role_id)
+        SELECT u.id, r.id FROM "user" u, roles r
+        WHERE u.email = $1 AND r.name IN ('user', 'admin')
+        ON CONFLICT DO NOTHING"#,
+        admin.email
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if let Some(id) = created {
+        let event = serde_json::json!({ "id": id, "email": admin.email, "roles": ["admin", "user"] });
+        outbox::enqueue(&mut tx, USER_REGISTERED, "user", id.to_string(), &event)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    tx.commit().await.map_err(|e| e.to_string())?;
+    Ok(if created.is_some() { AdminOutcome::Created } else { AdminOutcome::Reset })
+}
+
+/// Checks the loaded configuration and that Postgres, Redis and RabbitMQ
+/// answer. Returns non-zero when anything fails.
+pub async fn check_config(settings: &Settings) -> i32 {
+    println!("✅ configuration is valid");
+    let mut failed = false;
+
+    match JwtKeys::new(&settings.jwt) {
+        Ok(_) => println!("✅ jwt keys"),
+        Err(err) => {
+            println!("❌ jwt keys: {}", err);
+            failed = true;
+        }
+    }
+
+    let mut checks = HealthRegistry::default();
+    match PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(CHECK_TIMEOUT)
+        .connect_lazy(&settings.database.url)
+    {
+        Ok(pool) => checks.register(PostgresCheck(pool), CHECK_TIMEOUT, true),
+        Err(err) => {
+            println!("❌ postgres: {}", err);
+            failed = true;
+        }
+    }
+    match actix_web::rt::time::timeout(CHECK_TIMEOUT, redis_connect(&settings.redis)).await {
+        Ok(Ok(conn)) => checks.register(RedisCheck(conn), CHECK_TIMEOUT, true),
+        Ok(Err(err)) => {
+            println!("❌ redis: {}", err);
+            failed = true;
+        }
+        Err(_) => {
+            println!("❌ redis: timed out after {}ms", CHECK_TIMEOUT.as_millis());
+            failed = true;
+        }
+    }
+    checks.register(RabbitMqCheck(rabbit_connect(&settings.rabbitmq)), CHECK_TIMEOUT, true);
+
+    for component in checks.run().await.components {
+        match component.error {
+            None => println!("✅ {} ({}ms)", component.name, component.latency_ms),
+            Some(err) => {
+                println!("❌ {}: {}", component.name, err);
+                failed = true;
+            }
+        }
+    }
+
+    if failed { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        parse(&args.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parses_every_command() {
+        assert_eq!(parse_str(""), Ok(Command::Serve { migrate: false }));
+        assert_eq!(parse_str("serve"), Ok(Command::Serve { migrate: false }));
+        assert_eq!(parse_str("--migrate"), Ok(Command::Serve { migrate: true }));
+        assert_eq!(parse_str("serve --migrate"), Ok(Command::Serve { migrate: true }));
+        assert_eq!(parse_str("migrate up"), Ok(Command::Migrate(MigrateCommand::Up)));
+        assert_eq!(parse_str("migrate down"), Ok(Command::Migrate(MigrateCommand::Down { target: None })));
+        assert_eq!(
+            parse_str("migrate down --target 20261018000003"),
+            Ok(Command::Migrate(MigrateCommand::Down { target: Some(20261018000003) }))
+        );
+        assert_eq!(parse_str("migrate status"), Ok(Command::Migrate(MigrateCommand::Status)));
+        assert_eq!(parse_str("check-config"), Ok(Command::CheckConfig));
+    }
+
+    #[test]
+    fn create_admin_only_takes_over_accounts_when_asked() {
+        let admin = |reset_existing| Ok(Command::CreateAdmin { email: "a@example.com".to_string(), reset_existing });
+        assert_eq!(parse_str("create-admin --email a@example.com"), admin(false));
+        assert_eq!(parse_str("create-admin --email a@example.com --reset-existing"), admin(true));
+        assert_eq!(parse_str("create-admin --reset-existing --email a@example.com"), admin(true));
+    }
+
+    #[test]
+    fn rejects_unknown_or_incomplete_commands() {
+        for args in [
+            "migrate",
+            "migrate sideways",
+            "migrate down --target",
+            "create-admin",
+            "create-admin a@example.com",
+            "serve --fast",
+        ] {
+            assert!(parse_str(args).is_err(), "{:?} parsed", args);
+        }
+        assert_eq!(parse_str("migrate down --target latest").err().unwrap(), "invalid migration version latest");
+    }
+}
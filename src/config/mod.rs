@@ -0,0 +1 @@
+pub mod settings;
\ No newline at end of file
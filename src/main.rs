@@ -1,42 +1,44 @@
+mod cli;
+mod config;
 mod modules;
 mod utils;
 mod midleware;
 mod service;
 use std::{
-    collections::HashMap,
     env::{
         var_os,
-        set_var,
-        var
-    }
+        set_var
+    },
+    sync::Arc,
+    time::{Duration, Instant}
 };
 use actix_cors::Cors;
 use actix_web::{http::header,middleware::Logger,{
-    get, 
-    HttpResponse, 
-    Responder, 
     web::{self,scope}, 
     App, 
     HttpServer
 }};
-use lapin::{
-    BasicProperties,
-    types::FieldTable,
-    options::{
-    BasicPublishOptions, 
-    QueueDeclareOptions
-    }
-};
 
-use r2d2_redis::redis::Commands;
-use serde_json::json;
-use modules::post::post_handler::public_post_config;
+use config::settings::{Scope, Settings};
+use utils::{api_error::ApiError, jwt::JwtKeys};
+use modules::{
+    auth::auth_handler::auth_config, health::health_handler::health_config, post::post_handler::public_post_config,
+};
+use modules::post::post_events::{PostCacheHandler, CACHE_QUEUE};
+use service::consumer::ConsumerRegistry;
+use service::health::{HealthRegistry, PostgresCheck, RabbitMqCheck, RedisCheck};
+use service::scheduler::Scheduler;
+use service::topology::{Topology, TopologyError};
 
 /// Shared state for Actix App
 pub struct AppState {
     db: sqlx::Pool<sqlx::Postgres>,
-    redis: service::redis::RedisPool,
-    rabbit: service::rabbitmq::RabbitMqPool,
+    redis: service::redis::RedisConnection,
+    cache: service::redis::Cache,
+    events: Arc<dyn service::events::EventPublisher>,
+    settings: Settings,
+    jwt: JwtKeys,
+    health: Arc<HealthRegistry>,
 }
 
 #[actix_web::main]
@@ -47,21 +49,47 @@ async fn main() -> std::io::Result<()> {
     if var_os("RUST_LOG").is_none() {
         set_var("RUST_LOG", "actix_web=info");
     }
-    //get port from env
-    let port: u16 = var("PORT")
-                    .expect("cant get port from env")
-                    .parse::<u16>()
-                    .expect("cant convert port to u16");
+    let command = match cli::parse(&std::env::args().skip(1).collect::<Vec<_>>()) {
+        Ok(command) => command,
+        Err(err) => {
+            println!("{}\n{}",err,cli::USAGE);
+            std::process::exit(2)
+        }
+    };
 
-    // get database_url from env
-    let database_url: String = var("DATABASE_URL")
-                               .expect("cant get db url from env");
+    // load and validate configuration, the database commands need less of it
+    let scope = match command {
+        cli::Command::Serve { .. } | cli::Command::CheckConfig => Scope::Server,
+        cli::Command::Migrate(_) | cli::Command::CreateAdmin { .. } => Scope::Database,
+    };
+    let settings: Settings = match Settings::load(scope) {
+        Ok(settings) => settings,
+        Err(err) => {
+            println!("{}",err);
+            std::process::exit(1)
+        }
+    };
 
-    // create initial pool database
-    let pool: sqlx::Pool<sqlx::Postgres> = match sqlx::postgres::PgPoolOptions::new()
-        .min_connections(5)
-        .max_connections(50)
-        .connect(&database_url)
+    match command {
+        cli::Command::Serve { migrate } => serve(settings, migrate).await,
+        cli::Command::Migrate(command) => {
+            let pool = connect_database(&settings).await;
+            std::process::exit(cli::migrate(&pool, command).await)
+        }
+        cli::Command::CreateAdmin { email, reset_existing } => {
+            let pool = connect_database(&settings).await;
+            std::process::exit(cli::create_admin(&pool, &email, reset_existing).await)
+        }
+        cli::Command::CheckConfig => std::process::exit(cli::check_config(&settings).await),
+    }
+}
+
+/// Connects to postgres or exits, every command needs the database.
+async fn connect_database(settings: &Settings) -> sqlx::Pool<sqlx::Postgres> {
+    match sqlx::postgres::PgPoolOptions::new()
+        .min_connections(settings.database.min_connections)
+        .max_connections(settings.database.max_connections)
+        .connect(&settings.database.url)
         .await {
             Ok(pg_pool)=> {
                 println!("✅ Connection to the database is successful!");
@@ -71,22 +99,109 @@ async fn main() -> std::io::Result<()> {
             println!("failed to connect database: {}",err);
             std::process::exit(1)
             }
-        };
+        }
+}
+
+async fn serve(settings: Settings, migrate: bool) -> std::io::Result<()> {
+    let port: u16 = settings.server.port;
+
+    // build the jwt signing and verification keys
+    let jwt_keys: JwtKeys = match JwtKeys::new(&settings.jwt) {
+        Ok(keys) => keys,
+        Err(err) => {
+            println!("invalid jwt configuration: {}",err);
+            std::process::exit(1)
+        }
+    };
 
-    // Create redis connection pool
-    let redis_conn: r2d2_redis::r2d2::Pool<r2d2_redis::RedisConnectionManager> = service::redis::redis_connect();
+    // create initial pool database
+    let pool: sqlx::Pool<sqlx::Postgres> = connect_database(&settings).await;
+
+    if migrate || settings.database.migrate_on_startup {
+        if let Err(err) = service::migrations::up(&pool).await {
+            println!("failed to run migrations: {}",err);
+            std::process::exit(1)
+        }
+        println!("✅ Migrations are up to date");
+    }
+
+    // Create the shared redis connection
+    let redis_conn: service::redis::RedisConnection = match service::redis::redis_connect(&settings.redis).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            println!("failed to connect redis: {}",err);
+            std::process::exit(1)
+        }
+    };
+    // shared by every worker so concurrent misses collapse across threads
+    let cache = service::redis::Cache::new(redis_conn.clone());
 
     // Create rabbitmq connection pool
-    let rabbit_conn: deadpool_lapin::Pool = service::rabbitmq::rabbit_connect();
+    let rabbit_conn: deadpool_lapin::Pool = service::rabbitmq::rabbit_connect(&settings.rabbitmq);
+
+    let mut consumers = ConsumerRegistry::default();
+    consumers.register(CACHE_QUEUE, &["post.*"], PostCacheHandler { cache: cache.clone() });
+
+    // declare exchanges and queues up front, a conflicting declaration is a config error
+    let topology = Topology::new(&settings)
+        .and_then(|mut topology| {
+            consumers.declare(&mut topology, &settings.rabbitmq.exchange, &settings.consumer)?;
+            Ok(Arc::new(topology))
+        })
+        .unwrap_or_else(|err| {
+            println!("{}",err);
+            std::process::exit(1)
+        });
+    match topology.ensure(&rabbit_conn).await {
+        Ok(()) => println!("✅ RabbitMQ topology declared"),
+        Err(err @ TopologyError::Rejected(_)) => {
+            println!("{}",err);
+            std::process::exit(1)
+        }
+        // declared again before the first publish or consume
+        Err(err) => log::warn!("{}", err),
+    }
+
+    // dependencies checked by /readyz
+    let mut health = HealthRegistry::default();
+    health.register(PostgresCheck(pool.clone()), Duration::from_secs(2), true);
+    health.register(RedisCheck(redis_conn.clone()), Duration::from_secs(1), true);
+    health.register(RabbitMqCheck(rabbit_conn.clone()), Duration::from_secs(2), true);
+    let health = Arc::new(health);
+
+    // the broker events are published to, picked by EVENTS_BACKEND
+    let events = service::events::event_publisher(&settings, &rabbit_conn, &topology);
+
+    // background tasks run until the server has drained
+    let (stop_background, background_shutdown) = service::shutdown::shutdown_channel();
+    // publish domain events written by the handlers
+    let relay = actix_web::rt::spawn(service::outbox::run_relay(
+        pool.clone(),
+        events.clone(),
+        settings.outbox.clone(),
+        background_shutdown.clone(),
+    ));
+    let mut background = vec![("outbox relay".to_string(), relay)];
+    background.extend(consumers.spawn(&rabbit_conn, &topology, &settings.consumer, background_shutdown.clone()));
+    if settings.jobs.enabled {
+        background.extend(scheduled_jobs(&settings, &pool, &redis_conn, &cache).spawn(background_shutdown));
+    }
 
     // print the status server and the port
     println!("🚀 Server started successfully at port {:?}",port);
     
     // Start Actix server
-    HttpServer::new(move || {
+    let host: String = settings.server.host.clone();
+    let shutdown_timeout = settings.server.shutdown_timeout_secs;
+    let shutdown_drain = settings.server.shutdown_drain_secs;
+    let app_pool = pool.clone();
+    let app_events = events.clone();
+    let app_health = health.clone();
+    let server = HttpServer::new(move || {
         //configure the cors
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
+        let cors = settings.server.cors_origins.iter().fold(Cors::default(), |cors, origin| {
+            cors.allowed_origin(origin)
+        })
             .allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
             .allowed_headers(vec![
                 header::CONTENT_TYPE,
@@ -97,83 +212,114 @@ async fn main() -> std::io::Result<()> {
 
         App::new()
             .app_data(web::Data::new(AppState {
-                db: pool.clone(),
+                db: app_pool.clone(),
                 redis: redis_conn.clone(),
-                rabbit: rabbit_conn.clone(),
+                cache: cache.clone(),
+                events: app_events.clone(),
+                settings: settings.clone(),
+                jwt: jwt_keys.clone(),
+                health: app_health.clone(),
             }))
+            .app_data(web::JsonConfig::default().error_handler(|err, _| ApiError::from(err).into()))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| ApiError::from(err).into()))
             .wrap(cors)
             .wrap(Logger::default())
+            .configure(health_config)
             .service(
                 scope("/api")
-                    .service(api_health_check)
+                    .configure(auth_config)
                     .configure(public_post_config),
             )
     })
-    .bind(("0.0.0.0",port))?
-    .run()
-    .await
-}
+    .shutdown_timeout(shutdown_timeout)
+    // signals are handled below so readiness can fail before draining
+    .disable_signals()
+    .bind((host,port))?
+    .run();
 
-/// Health Check Endpoint
-#[get("/healthcheck")]
-pub async fn api_health_check(data: web::Data<AppState>) -> impl Responder {
-    // let mut message = String::new();
-    
-    let mut error_messages:Vec<HashMap<String,String>> = vec![];
-    // Database Health Check
-    match sqlx::query("SELECT 1;").fetch_one(&data.db).await {
-        Ok(_) => {
-            println!("✅ Database healthy");
-        }
-        Err(err) => {
-            let mut error_message:HashMap<String,String> = HashMap::new();
-            error_message.insert("database".to_string(), format!("cant connect to database {}",err).to_string());
-            error_messages.push(error_message);
-        }
+    let handle = server.handle();
+    let signal_health = health.clone();
+    actix_web::rt::spawn(async move {
+        let signal = service::shutdown::wait_for_signal().await;
+        println!("received {}, shutting down (drain {}s, timeout {}s)", signal, shutdown_drain, shutdown_timeout);
+        signal_health.begin_shutdown();
+        // keep serving while load balancers see readiness fail and stop routing here
+        actix_web::rt::time::sleep(Duration::from_secs(shutdown_drain)).await;
+        let started = Instant::now();
+        handle.stop(true).await;
+        println!("http server drained in {}ms", started.elapsed().as_millis());
+    });
+
+    server.await?;
+
+    // the server has drained, let the relay, consumers and jobs finish their work then close everything behind them
+    let started = Instant::now();
+    let _ = stop_background.send(());
+    let task_count = background.len();
+    let unfinished = service::shutdown::join_within(background, Duration::from_secs(shutdown_timeout)).await;
+    for name in &unfinished {
+        println!("{} did not stop within {}s, aborted", name, shutdown_timeout);
     }
+    let rabbit_closed = service::rabbitmq::rabbit_close(&rabbit_conn).await;
+    pool.close().await;
+    println!(
+        "🛑 Shutdown complete, {} of {} background task(s) stopped and pools closed in {}ms: {} rabbitmq connection(s) closed, postgres pool closed",
+        task_count - unfinished.len(),
+        task_count,
+        started.elapsed().as_millis(),
+        rabbit_closed,
+    );
+    Ok(())
+}
+
+/// Recurring maintenance, schedules come from `JOBS_*`.
+fn scheduled_jobs(
+    settings: &Settings,
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    redis: &service::redis::RedisConnection,
+    cache: &service::redis::Cache,
+) -> Scheduler {
+    let jobs = &settings.jobs;
+    let mut scheduler = Scheduler::new(pool.clone());
 
-    // Redis Health Check
-    match data.redis.get() {
-        Ok(mut conn) => {
-            let _: () = conn.set("testing_redis", "yoo").expect("Failed to set Redis key");
-            let redis_value: String = conn.get("testing_redis").expect("Failed to get Redis key");
-            println!("✅ Redis healthy: {}", redis_value);
+    let conn = redis.clone();
+    scheduler.register("purge_refresh_tokens", &jobs.purge_refresh_tokens, move || {
+        let conn = conn.clone();
+        async move {
+            let deleted = service::token_store::purge_stale(&conn).await?;
+            Ok(format!("{} stale token key(s) deleted", deleted))
         }
-        Err(err) => {
-            let mut error_message:HashMap<String,String> = HashMap::new();
-            error_message.insert("redis".to_string(), format!("cant connect to redis {}",err).to_string());
-            error_messages.push(error_message);
+    });
+
+    let db = pool.clone();
+    let retention_hours = jobs.outbox_retention_hours;
+    scheduler.register("prune_outbox", &jobs.prune_outbox, move || {
+        let db = db.clone();
+        async move {
+            let deleted = service::outbox::prune(&db, retention_hours).await?;
+            Ok(format!("{} published event(s) deleted", deleted))
         }
-    }
+    });
 
-    // RabbitMQ Health Check
-    match data.rabbit.get().await {
-        Ok(conn) => {
-            let channel = conn.create_channel().await.expect("Failed to create channel");
-                channel
-                .queue_declare("test_queue", QueueDeclareOptions::default(), FieldTable::default())
-                .await
-                .expect("Failed to declare queue");
-            let _ = channel
-                .basic_publish(
-                    "",
-                    "test_queue",
-                    BasicPublishOptions::default(),
-                    b"Hello, RabbitMQ!",
-                    BasicProperties::default(),
-                )
-                .await
-                .expect("Failed to publish message");
-                println!("✅ RabbitMQ is healthy");
+    let (db, cache) = (pool.clone(), cache.clone());
+    let pages = jobs.warm_pages;
+    scheduler.register("warm_post_pages", &jobs.warm_post_pages, move || {
+        let (db, cache) = (db.clone(), cache.clone());
+        async move {
+            let loaded = modules::post::post_cache::warm(&db, &cache, pages).await?;
+            Ok(format!("{} of {} page(s) loaded", loaded, pages))
         }
-        Err(err) => {
-            let mut error_message:HashMap<String,String> = HashMap::new();
-            error_message.insert("redis".to_string(), format!("cant connect to redis {}",err).to_string());
-            error_messages.push(error_message);
+    });
+
+    let db = pool.clone();
+    scheduler.register("refresh_stats", &jobs.refresh_stats, move || {
+        let db = db.clone();
+        async move {
+            // concurrently, so readers of the view are never blocked
+            sqlx::query("REFRESH MATERIALIZED VIEW CONCURRENTLY post_stats").execute(&db).await?;
+            Ok("post_stats refreshed".to_string())
         }
-    }
-    if error_messages.len() >0{
-        return HttpResponse::BadRequest().json(json!({"error":error_messages}));
-    }
-    HttpResponse::Ok().json(json!({ "status": "success", "message": "API healthy and ready to go 🚀🚀" }))
+    });
+
+    scheduler
 }
@@ -0,0 +1,44 @@
+use actix_web::{dev::Payload, web, FromRequest, HttpMessage, HttpRequest};
+use futures::future::LocalBoxFuture;
+use std::ops::Deref;
+
+use super::authmiddlewares::authenticate;
+use crate::utils::api_error::ApiError;
+use crate::modules::auth::auth_models::UserPayload;
+use crate::AppState;
+
+/// The user behind the request's bearer token.
+///
+/// Reuses what the `Authentication` middleware already verified when it is
+/// wrapped around the route, otherwise verifies the token itself.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser(pub UserPayload);
+
+impl Deref for AuthenticatedUser {
+    type Target = UserPayload;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+                return Ok(user.clone());
+            }
+
+            let data = req
+                .app_data::<web::Data<AppState>>()
+                .ok_or_else(|| ApiError::Internal("AppState is not configured".to_string()))?;
+            let token = authenticate(req.headers(), data).await?;
+            Ok(AuthenticatedUser(token.user))
+        })
+    }
+}
@@ -1,18 +1,23 @@
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage, HttpResponse, ResponseError,
+    http::header::{HeaderMap, AUTHORIZATION},
+    web, Error, HttpMessage,
 };
 use futures::future::{ok, LocalBoxFuture, Ready};
-use serde_json::json;
 use std::fmt;
+use std::rc::Rc;
 
-use crate::utils::jwt::decode_token;
+use super::authenticated_user::AuthenticatedUser;
+use crate::service::token_store::is_revoked;
+use crate::utils::api_error::ApiError;
+use crate::utils::jwt::{decode_token, JwtUserToken, TokenError};
+use crate::AppState;
 
 pub struct Authentication;
 
 impl<S, B> Transform<S, ServiceRequest> for Authentication
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -23,17 +28,17 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(AuthMiddleware { service })
+        ok(AuthMiddleware { service: Rc::new(service) })
     }
 }
 
 pub struct AuthMiddleware<S> {
-    service: S,
+    service: Rc<S>,
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -44,41 +49,96 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let auth_header = req.headers().get("AUTHORIZATION").cloned();
-
-        if let Some(auth_value) = auth_header {
-            if let Ok(auth_str) = auth_value.to_str() {
-                if auth_str.starts_with("Bearer ") {
-                    let token = auth_str.trim_start_matches("Bearer ").trim();
-                    let decode_token = decode_token(token.to_string()).expect("token invalid");
-                     println!("{}from auth middleware",decode_token.claims.user.id);
-                    req.extensions_mut().insert(decode_token.claims.user.id);
-                        let fut = self.service.call(req);
-                        return Box::pin(async move {
-                            let res = fut.await?;
-                            Ok(res)
-                        });
-
-                }
-            }
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let data = req
+                .app_data::<web::Data<AppState>>()
+                .cloned()
+                .ok_or_else(|| ApiError::Internal("AppState is not configured".to_string()))?;
+            let token = authenticate(req.headers(), &data).await.map_err(ApiError::from)?;
+            req.extensions_mut().insert(AuthenticatedUser(token.user));
+
+            let res = service.call(req).await?;
+            Ok(res)
+        })
+    }
+}
+
+/// Reads and verifies the bearer token of a request, including the
+/// revocation check, and returns its claims.
+pub async fn authenticate(headers: &HeaderMap, data: &AppState) -> Result<JwtUserToken, UnauthorizedError> {
+    let auth_value = headers.get(AUTHORIZATION).ok_or(UnauthorizedError::MissingToken)?;
+    let token = auth_value
+        .to_str()
+        .ok()
+        .and_then(|auth_str| auth_str.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .ok_or(UnauthorizedError::Malformed)?;
+
+    let claims = decode_token(token.to_string(), &data.jwt)?.claims;
+    match is_revoked(&data.redis, &claims.jti, &claims.sid).await {
+        Ok(false) => Ok(claims),
+        Ok(true) => Err(UnauthorizedError::Revoked),
+        Err(err) => {
+            log::error!("revocation check failed: {:?}", err);
+            Err(UnauthorizedError::Unavailable)
         }
+    }
+}
 
-        Box::pin(async { Err(UnauthorizedError.into()) })
+/// Why a request could not be authenticated, rendered through `ApiError`.
+#[derive(Debug, PartialEq)]
+pub enum UnauthorizedError {
+    MissingToken,
+    Malformed,
+    Expired,
+    InvalidSignature,
+    Revoked,
+    RefreshInvalid,
+    RefreshReused,
+    Unavailable,
+}
+
+impl UnauthorizedError {
+    /// Stable, machine readable reason sent as `code` in the response body.
+    pub fn code(&self) -> &'static str {
+        match self {
+            UnauthorizedError::MissingToken => "missing_token",
+            UnauthorizedError::Malformed => "malformed_token",
+            UnauthorizedError::Expired => "token_expired",
+            UnauthorizedError::InvalidSignature => "invalid_signature",
+            UnauthorizedError::Revoked => "token_revoked",
+            UnauthorizedError::RefreshInvalid => "invalid_refresh_token",
+            UnauthorizedError::RefreshReused => "refresh_token_reused",
+            UnauthorizedError::Unavailable => "auth_unavailable",
+        }
     }
 }
 
-#[derive(Debug)]
-struct UnauthorizedError;
+impl From<TokenError> for UnauthorizedError {
+    fn from(err: TokenError) -> Self {
+        match err {
+            TokenError::Expired => UnauthorizedError::Expired,
+            TokenError::InvalidSignature => UnauthorizedError::InvalidSignature,
+            TokenError::Malformed => UnauthorizedError::Malformed,
+        }
+    }
+}
 
 impl fmt::Display for UnauthorizedError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Unauthorized: Invalid or missing token")
+        let message = match self {
+            UnauthorizedError::MissingToken => "Authorization header is missing",
+            UnauthorizedError::Malformed => "Authorization header must be a well formed Bearer token",
+            UnauthorizedError::Expired => "Token has expired",
+            UnauthorizedError::InvalidSignature => "Token signature is invalid",
+            UnauthorizedError::Revoked => "Token has been revoked",
+            UnauthorizedError::RefreshInvalid => "Refresh token is invalid or expired",
+            UnauthorizedError::RefreshReused => "Refresh token was already used, please login again",
+            UnauthorizedError::Unavailable => "Token could not be verified, try again later",
+        };
+        write!(f, "{}", message)
     }
 }
-
-impl ResponseError for UnauthorizedError {
-    fn error_response(&self) -> HttpResponse {
-        HttpResponse::Unauthorized()
-            .json(json!({"error": "Unauthorized", "message": self.to_string()}))
-    }
-}
\ No newline at end of file
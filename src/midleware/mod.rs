@@ -1 +1,3 @@
-// pub mod authmiddlewares;
\ No newline at end of file
+pub mod authmiddlewares;
+pub mod authenticated_user;
+pub mod permission;
\ No newline at end of file
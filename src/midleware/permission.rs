@@ -0,0 +1,78 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, HttpMessage,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::rc::Rc;
+
+use super::authenticated_user::AuthenticatedUser;
+use super::authmiddlewares::authenticate;
+use crate::utils::api_error::ApiError;
+use crate::AppState;
+
+/// Rejects the request with 403 unless the caller's token grants the
+/// permission, e.g. `.wrap(RequirePermission("post:delete"))`. Authenticates
+/// the request itself when `Authentication` is not wrapped around it.
+pub struct RequirePermission(pub &'static str);
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = PermissionMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(PermissionMiddleware { service: Rc::new(service), permission: self.0 })
+    }
+}
+
+pub struct PermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for PermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let permission = self.permission;
+
+        Box::pin(async move {
+            let existing = req.extensions().get::<AuthenticatedUser>().cloned();
+            let user = match existing {
+                Some(user) => user,
+                None => {
+                    let data = req
+                        .app_data::<web::Data<AppState>>()
+                        .ok_or_else(|| ApiError::Internal("AppState is not configured".to_string()))?;
+                    let token = authenticate(req.headers(), data).await.map_err(ApiError::from)?;
+                    AuthenticatedUser(token.user)
+                }
+            };
+
+            if !user.has_permission(permission) {
+                return Err(ApiError::MissingPermission(permission).into());
+            }
+            req.extensions_mut().insert(user);
+
+            let res = service.call(req).await?;
+            Ok(res)
+        })
+    }
+}
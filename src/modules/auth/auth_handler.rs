@@ -1,116 +1,205 @@
-use actix_web::{post, web, Error, HttpResponse, Responder};
+use actix_web::{get, http::header, post, web, HttpRequest, HttpResponse};
 use argon2::{password_hash::{rand_core::OsRng, SaltString}, Argon2, PasswordHasher, PasswordVerifier};
 use serde_json::json;
 use sqlx::query_as;
-use validator::Validate;
 use crate::AppState;
-use crate::utils::jwt::TokenClaims;
-use super::auth_models::{Register,Login,User,UserPayload};
+use crate::midleware::{authenticated_user::AuthenticatedUser, authmiddlewares::UnauthorizedError};
+use crate::service::outbox::{self, USER_REGISTERED};
+use crate::service::token_store::{
+    consume_refresh_token, find_refresh_token, revoke_family, revoke_jti, store_refresh_token,
+    RefreshOutcome, RefreshSession,
+};
+use crate::utils::api_error::ApiError;
+use crate::utils::validated_json::ValidatedJson;
+use crate::utils::jwt::{decode_token, generate_refresh_token, hash_refresh_token, TokenClaims};
+use super::auth_models::{Register,Login,RefreshRequest,User,UserPayload};
 
 #[post("/register")]
 pub async fn register(
-    body: web::Json<Register>,
+    body: ValidatedJson<Register>,
     db_conn: web::Data<AppState>,
-) -> impl Responder {
-    // Generate salt and hash password
-    let salt: SaltString = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-
-    let password_hash = match argon2.hash_password(body.password.as_bytes(), &salt) {
-        Ok(hash) => hash.to_string(),
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "status": "error",
-                "message": format!("Error hashing password: {}", e)
-            }))
-        }
-    };
+) -> Result<HttpResponse, ApiError> {
+    let password_hash = hash_password(&body.password)?;
 
     let mut user_input = body.into_inner();
-
-    // Validate input
-    if let Err(errors) = user_input.validate() {
-        return HttpResponse::BadRequest().json(json!({
-            "status": "failed",
-            "message": errors
-        }));
-    }
-
     user_input.password = password_hash;
 
-    // Insert user into database
+    // Insert user into database with the default role
+    let mut tx = db_conn.db.begin().await?;
     let new_user = query_as!(
-        Register,
-        r#"INSERT INTO "user" (email, password) VALUES ($1, $2) RETURNING *"#,
+        UserPayload,
+        r#"WITH new_user AS (
+            INSERT INTO "user" (email, password) VALUES ($1, $2) RETURNING id, email
+        ), default_role AS (
+            INSERT INTO user_roles (user_id, role_id)
+            SELECT new_user.id, roles.id FROM new_user, roles WHERE roles.name = 'user'
+            RETURNING role_id
+        )
+        SELECT new_user.id AS "id!", new_user.email AS "email!",
+            ARRAY(SELECT r.name FROM roles r JOIN default_role d ON d.role_id = r.id)::text[] AS "roles!",
+            ARRAY(
+                SELECT p.name FROM permissions p
+                JOIN role_permissions rp ON rp.permission_id = p.id
+                JOIN default_role d ON d.role_id = rp.role_id
+            )::text[] AS "permissions!"
+        FROM new_user"#,
         user_input.email,
         user_input.password
     )
-    .fetch_one(&db_conn.db)
-    .await;
-    match new_user {
-        Ok(user) => {
-            let payload:UserPayload = UserPayload {
-                id: user.id.expect("invalid uuid"),
-                email: user.email,
-            };
-
-            HttpResponse::Created().json(json!({
-                "status": "success",
-                "data":payload
-            }))
-        },            
-        Err(err) => {
-            if err.to_string().contains("duplicate key value violates unique constraint") {
-                HttpResponse::BadRequest().json(json!({
-                    "status": "failed",
-                    "message": "User with that email or username already exists"
-                }))
-            } else {
-                HttpResponse::InternalServerError().json(json!({
-                    "status": "error",
-                    "message": format!("{:?}", err)
-                }))
-            }
-        }
-    }
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| ApiError::from(e).on_conflict("User with that email already exists"))?;
+    outbox::enqueue(&mut tx, USER_REGISTERED, "user", new_user.id.to_string(), &json!({
+        "id": new_user.id,
+        "email": new_user.email,
+        "roles": new_user.roles,
+    })).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(json!({
+        "status": "success",
+        "data":new_user
+    })))
+}
+
+/// Hashes a password with a fresh salt, the same way for every account.
+pub fn hash_password(password:&str) -> Result<String, ApiError> {
+    let salt: SaltString = SaltString::generate(&mut OsRng);
+    let argon2 = Argon2::default();
+
+    Ok(argon2.hash_password(password.as_bytes(), &salt)
+        .map_err(|e| ApiError::Internal(format!("Error hashing password: {}", e)))?
+        .to_string())
 }
 
 #[post("/login")]
 pub async fn login(
-    body:web::Json<Login>,
+    body:ValidatedJson<Login>,
     db_conn:web::Data<AppState>
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let user_result = query_as!(
         User,
-        r#"SELECT * FROM "user" WHERE email = $1"#,
+        r#"SELECT u.id, u.email, u.password,
+            ARRAY(
+                SELECT r.name FROM user_roles ur JOIN roles r ON r.id = ur.role_id
+                WHERE ur.user_id = u.id ORDER BY r.name
+            )::text[] AS "roles!",
+            ARRAY(
+                SELECT DISTINCT p.name FROM user_roles ur
+                JOIN role_permissions rp ON rp.role_id = ur.role_id
+                JOIN permissions p ON p.id = rp.permission_id
+                WHERE ur.user_id = u.id ORDER BY p.name
+            )::text[] AS "permissions!"
+        FROM "user" u WHERE u.email = $1"#,
         body.email
     )
-    .fetch_one(&db_conn.db)
-    .await;
-
-    match user_result{
-        Ok(user)=>{
-            let password_hash = user.password;
-            let argon2 = Argon2::default();
-            let parsed_hash = argon2::PasswordHash::new(&password_hash).unwrap();
-            let result = argon2.verify_password(body.password.as_bytes(), &parsed_hash);
-
-            match result {
-            Ok(_)=>{
-                let user_payload :UserPayload = UserPayload{
-                    id:user.id,
-                    email:user.email
-                };
-                let token:String= TokenClaims::generate_token(user_payload).unwrap();
-                Ok::<HttpResponse, Error>(HttpResponse::Ok().json(json!({"status":"success","token":token,"message":"login success"})))
-            },
-            Err(_err) => Ok(HttpResponse::Unauthorized().json(json!({"message":"error when login"})))
-            }
-        },
-        Err(_err)=>{
-            Ok(HttpResponse::Unauthorized().json(json!({"message":"error when login"})))
-        }
+    .fetch_optional(&db_conn.db)
+    .await?
+    .ok_or(ApiError::InvalidCredentials)?;
+
+    let argon2 = Argon2::default();
+    let parsed_hash = argon2::PasswordHash::new(&user_result.password)
+        .map_err(|e| ApiError::Internal(format!("stored password hash is invalid: {}", e)))?;
+    argon2.verify_password(body.password.as_bytes(), &parsed_hash)
+        .map_err(|_| ApiError::InvalidCredentials)?;
+
+    let user_payload :UserPayload = UserPayload{
+        id:user_result.id,
+        email:user_result.email,
+        roles:user_result.roles,
+        permissions:user_result.permissions
+    };
+    let family = uuid::Uuid::new_v4().to_string();
+    let tokens = issue_tokens(&db_conn, user_payload, family).await?;
+
+    Ok(HttpResponse::Ok().json(json!({"status":"success","data":tokens,"message":"login success"})))
+}
+
+/// Trades a refresh token for a new token pair. The old refresh token is
+/// spent; presenting it again revokes every token of its family.
+#[post("/refresh")]
+pub async fn refresh(
+    body:web::Json<RefreshRequest>,
+    db_conn:web::Data<AppState>
+) -> Result<HttpResponse, ApiError> {
+    let token_hash = hash_refresh_token(&body.refresh_token);
+    let session = match consume_refresh_token(&db_conn.redis, &token_hash, db_conn.settings.jwt.refresh_max_age).await? {
+        RefreshOutcome::Rotated(session) => session,
+        RefreshOutcome::Reused => return Err(UnauthorizedError::RefreshReused.into()),
+        RefreshOutcome::Invalid => return Err(UnauthorizedError::RefreshInvalid.into()),
+    };
+
+    // reload roles so permission changes apply from the next access token
+    let user = find_user_payload(&db_conn.db, session.user.id).await?
+        .ok_or(ApiError::Unauthorized(UnauthorizedError::RefreshInvalid))?;
+    let tokens = issue_tokens(&db_conn, user, session.family).await?;
+
+    Ok(HttpResponse::Ok().json(json!({"status":"success","data":tokens})))
+}
+
+/// Revokes the refresh token family and, when sent, the current access token.
+#[post("/logout")]
+pub async fn logout(
+    req:HttpRequest,
+    body:web::Json<RefreshRequest>,
+    db_conn:web::Data<AppState>
+) -> Result<HttpResponse, ApiError> {
+    let ttl = db_conn.settings.jwt.refresh_max_age;
+
+    if let Some(session) = find_refresh_token(&db_conn.redis, &hash_refresh_token(&body.refresh_token)).await? {
+        revoke_family(&db_conn.redis, &session.family, ttl).await?;
+    }
+
+    let bearer = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(Ok(token)) = bearer.map(|token| decode_token(token.trim().to_string(), &db_conn.jwt)) {
+        let remaining = token.claims.exp - chrono::Utc::now().timestamp();
+        revoke_jti(&db_conn.redis, &token.claims.jti, remaining).await?;
     }
+
+    Ok(HttpResponse::Ok().json(json!({"status":"success","message":"logout success"})))
+}
+
+#[get("/me")]
+pub async fn me(user:AuthenticatedUser) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(json!({"status":"success","data":user.0})))
+}
+
+async fn find_user_payload(db:&sqlx::PgPool, id:uuid::Uuid) -> Result<Option<UserPayload>, sqlx::Error> {
+    query_as!(
+        UserPayload,
+        r#"SELECT u.id, u.email,
+            ARRAY(
+                SELECT r.name FROM user_roles ur JOIN roles r ON r.id = ur.role_id
+                WHERE ur.user_id = u.id ORDER BY r.name
+            )::text[] AS "roles!",
+            ARRAY(
+                SELECT DISTINCT p.name FROM user_roles ur
+                JOIN role_permissions rp ON rp.role_id = ur.role_id
+                JOIN permissions p ON p.id = rp.permission_id
+                WHERE ur.user_id = u.id ORDER BY p.name
+            )::text[] AS "permissions!"
+        FROM "user" u WHERE u.id = $1"#,
+        id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Signs an access token and stores a new refresh token in `family`.
+async fn issue_tokens(data:&AppState, user:UserPayload, family:String) -> Result<serde_json::Value, ApiError> {
+    let token = TokenClaims::generate_token(user.clone(), &family, &data.jwt)?;
+    let refresh_token = generate_refresh_token();
+    let session = RefreshSession { user, family };
+    store_refresh_token(&data.redis, &hash_refresh_token(&refresh_token), &session, data.settings.jwt.refresh_max_age).await?;
+
+    Ok(json!({
+        "token": token,
+        "token_type": "Bearer",
+        "expires_in": data.jwt.max_age,
+        "refresh_token": refresh_token,
+    }))
 }
 
 pub fn auth_config(config:&mut web::ServiceConfig){
@@ -118,5 +207,8 @@ pub fn auth_config(config:&mut web::ServiceConfig){
         web::scope("/auth")
         .service(register)
         .service(login)
+        .service(refresh)
+        .service(logout)
+        .service(me)
     );
 }
\ No newline at end of file
@@ -4,7 +4,6 @@ use validator::Validate;
 
 #[derive(Deserialize,Serialize,Validate)]
 pub struct Register {
-    pub id:Option<Uuid>,
     #[validate(email(message="email must be valid"))]
     pub email:String,
     #[validate(length(min="8",message="please add your password"))]
@@ -15,17 +14,36 @@ pub struct Register {
 pub struct User {
     pub id:Uuid,
     pub email:String,
-    pub password:String
+    pub password:String,
+    pub roles:Vec<String>,
+    pub permissions:Vec<String>
 }
 
-#[derive(Deserialize,Serialize)]
+#[derive(Deserialize,Serialize,Validate)]
 pub struct Login {
+    #[validate(email(message="email must be valid"))]
     pub email:String,
+    #[validate(length(min="1",message="please add your password"))]
     pub password:String
 }
 
-#[derive(Debug,Deserialize,Serialize)]
+#[derive(Deserialize,Serialize)]
+pub struct RefreshRequest {
+    pub refresh_token:String
+}
+
+#[derive(Debug,Clone,Deserialize,Serialize)]
 pub struct UserPayload {
     pub id: Uuid,
     pub email: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+impl UserPayload {
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
 }
\ No newline at end of file
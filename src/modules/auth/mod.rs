@@ -1,2 +1,2 @@
-// pub mod auth_models;
-// pub mod auth_handler;
\ No newline at end of file
+pub mod auth_models;
+pub mod auth_handler;
\ No newline at end of file
@@ -0,0 +1,33 @@
+use actix_web::{get, web, HttpResponse};
+use serde_json::json;
+
+use crate::AppState;
+
+/// The process is up and serving requests, dependencies are not checked.
+#[get("/livez")]
+pub async fn livez() -> HttpResponse {
+    HttpResponse::Ok().json(json!({"status":"up"}))
+}
+
+/// Whether this instance can serve traffic: 503 when a required dependency
+/// is down, with the status and latency of every component.
+#[get("/readyz")]
+pub async fn readyz(data: web::Data<AppState>) -> HttpResponse {
+    let report = data.health.run().await;
+    let body = json!({
+        "status": report.status,
+        "components": report.components,
+        "cache": data.cache.stats(),
+        "events": data.events.backend(),
+    });
+
+    if report.is_ready() {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+pub fn health_config(config: &mut web::ServiceConfig) {
+    config.service(livez).service(readyz);
+}
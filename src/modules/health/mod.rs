@@ -0,0 +1 @@
+pub mod health_handler;
\ No newline at end of file
@@ -1,2 +1,3 @@
-// pub mod auth;
-pub mod post;
\ No newline at end of file
+pub mod auth;
+pub mod post;
+pub mod health;
\ No newline at end of file
@@ -1,2 +1,4 @@
 pub mod post_models;
-pub mod post_handler;
\ No newline at end of file
+pub mod post_handler;
+pub mod post_cache;pub mod post_search;
+pub mod post_events;
\ No newline at end of file
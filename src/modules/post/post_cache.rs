@@ -0,0 +1,76 @@
+use sqlx::PgPool;
+
+use super::post_models::Post;
+use crate::service::redis::{Cache, Cached};
+use crate::utils::api_error::ApiError;
+use crate::AppState;
+
+/// Posts per page of `/getall/{page}`.
+pub const PAGE_SIZE: i64 = 10;
+
+/// Cached pages and posts live for 5 minutes at most.
+const CACHE_TTL: u64 = 60 * 5;
+
+/// Ids that do not exist are remembered briefly, a create clears them.
+const MISSING_TTL: u64 = 30;
+
+/// Bumped on every write. Page keys embed the current version, so a bump
+/// makes every cached page unreachable at once and they expire on their own.
+const LIST_VERSION_KEY: &str = "posts_list_version";
+
+fn page_key(version: i64, page: i64) -> String {
+    format!("posts_page_v{}_{}", version, page)
+}
+
+fn post_key(id: i32) -> String {
+    format!("post_{}", id)
+}
+
+pub async fn get_page(db: &PgPool, cache: &Cache, page: i64, limit: i64) -> Result<Cached<Vec<Post>>, ApiError> {
+    let offset = (page - 1)
+        .checked_mul(limit)
+        .ok_or(ApiError::BadRequest("page is too large".to_string()))?;
+    // read before the database so a write in between never gets cached as fresh
+    let key = page_key(cache.counter(LIST_VERSION_KEY).await, page);
+    cache.get_or_load(&key, CACHE_TTL, || async {
+        let posts = sqlx::query_as!(
+            Post,
+            r#"SELECT id, title, content, create_at, updated_at, author_id FROM post
+            ORDER BY id LIMIT $1 OFFSET $2"#,
+            limit,
+            offset,
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(posts)
+    })
+    .await
+}
+
+pub async fn get_post(data: &AppState, id: i32) -> Result<Cached<Option<Post>>, ApiError> {
+    data.cache.get_or_load_optional(&post_key(id), CACHE_TTL, MISSING_TTL, || async {
+        let post = sqlx::query_as!(Post, "SELECT id, title, content, create_at, updated_at, author_id FROM post WHERE id = $1", id)
+            .fetch_optional(&data.db)
+            .await?;
+        Ok(post)
+    })
+    .await
+}
+
+/// Loads the first `pages` pages into the cache unless they are cached
+/// already, returns how many were loaded.
+pub async fn warm(db: &PgPool, cache: &Cache, pages: i64) -> Result<i64, ApiError> {
+    let mut loaded = 0;
+    for page in 1..=pages {
+        if !get_page(db, cache, page, PAGE_SIZE).await?.hit {
+            loaded += 1;
+        }
+    }
+    Ok(loaded)
+}
+
+/// Drops every cached page and the cached post itself.
+pub async fn invalidate(cache: &Cache, id: i32) {
+    cache.bump(LIST_VERSION_KEY).await;
+    cache.invalidate(&[post_key(id)]).await;
+}
@@ -0,0 +1,42 @@
+use futures::future::LocalBoxFuture;
+use serde::Deserialize;
+
+use super::post_cache;
+use crate::service::consumer::{Delivery, Handler, HandlerError};
+use crate::service::events::EventEnvelope;
+use crate::service::redis::Cache;
+
+/// Queue of `PostCacheHandler`, shared by every replica.
+pub const CACHE_QUEUE: &str = "post.cache";
+
+/// Only the id of the post in `post.*` events is needed here.
+#[derive(Deserialize)]
+pub struct PostRef {
+    pub id: i32,
+}
+
+/// Invalidates the cache again once a write is published. A read that
+/// loaded the old row just before the write committed may have cached it
+/// after the handler invalidated, this clears it.
+pub struct PostCacheHandler {
+    pub cache: Cache,
+}
+
+impl Handler for PostCacheHandler {
+    type Message = EventEnvelope<PostRef>;
+
+    fn handle(&self, delivery: Delivery<Self::Message>) -> LocalBoxFuture<'_, Result<(), HandlerError>> {
+        Box::pin(async move {
+            // the message id is the event id, it ties redeliveries of one event together
+            log::debug!(
+                "{} {} for post {} (retry {})",
+                delivery.routing_key,
+                delivery.message_id.as_deref().unwrap_or("-"),
+                delivery.message.data.id,
+                delivery.retries,
+            );
+            post_cache::invalidate(&self.cache, delivery.message.data.id).await;
+            Ok(())
+        })
+    }
+}
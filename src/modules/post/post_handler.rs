@@ -1,235 +1,211 @@
 use crate::AppState;
-use super::post_models::{NewPost,Post,UpdatePost};
-use actix_web::{delete, get, patch, post, web, HttpResponse, Responder};
-use r2d2_redis::redis::Commands;
+use crate::midleware::{
+    authenticated_user::AuthenticatedUser, authmiddlewares::Authentication, permission::RequirePermission,
+};
+use crate::service::outbox::{self, POST_CREATED, POST_DELETED, POST_UPDATED};
+use crate::utils::api_error::ApiError;
+use crate::utils::validated_json::ValidatedJson;
+use super::{post_cache, post_search};
+use super::post_models::{NewPost,Post,PostListQuery,UpdatePost};
+use actix_web::{delete, get, patch, post, web, HttpResponse};
 use serde_json::json;
-use sqlx::{query, query_as};
+use sqlx::query_as;
+use validator::Validate;
+
+/// Lists posts `limit` at a time. Follow `next_cursor` until `has_more` is
+/// false; unlike page numbers it never skips or repeats a post when posts
+/// are added in between. See `PostListQuery` for search, filters and sort.
+#[get("")]
+pub async fn list_posts(
+    query: web::Query<PostListQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    query.validate()?;
+    let page = post_search::list_posts(&data.db, &query).await?;
+
+    let mut body = json!({
+        "status": "ok",
+        "data": page.posts,
+        "next_cursor": page.next_cursor,
+        "has_more": page.has_more,
+    });
+    if let Some(total) = page.total {
+        body["total"] = json!(total);
+    }
+
+    Ok(HttpResponse::Ok().json(body))
+}
 
 #[get("/getall/{page}")]
 pub async fn get_all_post(
     path: web::Path<i64>,
     data: web::Data<AppState>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let page = path.into_inner();
-    let limit: i64 = 10;
-    let offset = (page - 1) * limit;
-
-    // Membuat key cache berdasarkan halaman
-    let redis_key = format!("posts_page_{}", page);
+    if page < 1 {
+        return Err(ApiError::BadRequest("page starts at 1".to_string()));
+    }
 
-    // Mengakses Redis connection dari AppState
-    let mut redis_conn = data.redis.get().expect("cant connect to redis");
+    // Ambil dari cache Redis, query ke database jika belum ada
+    let posts = post_cache::get_page(&data.db, &data.cache, page, post_cache::PAGE_SIZE).await?;
 
-    // Cek cache Redis
-    match redis_conn.get::<String, String>(redis_key.clone()) {
-        Ok(posts) if !posts.is_empty() => {
-            // Jika data ditemukan di cache
-            let posts: serde_json::Value = serde_json::from_str(&posts).unwrap_or_default();
-            return HttpResponse::Ok().json(json!({
-                "status": "ok",
-                "data": posts,
-                "source": "cache"
-            }));
-        }
-        _ => {
-            // Jika tidak ditemukan di cache, query ke database
-            let posts = sqlx::query_as!(
-                Post,
-                r#"SELECT * FROM post ORDER BY id LIMIT $1 OFFSET $2"#,
-                limit,
-                offset,
-            )
-            .fetch_all(&data.db)
-            .await;
-
-            match posts {
-                Ok(posts) => {
-                    let posts_json = serde_json::to_string(&posts).unwrap_or_default();
-
-                    // Simpan hasil query ke Redis dengan TTL 5 menit
-                    let _: () = redis_conn.set_ex(&redis_key, posts_json, 60 * 5).unwrap();
-
-                    HttpResponse::Ok().json(json!({
-                        "status": "ok",
-                        "data": posts,
-                        "source": "database"
-                    }))
-                }
-                Err(_) => {
-                    // Jika gagal query ke database
-                    HttpResponse::InternalServerError().json(json!({
-                        "status": "error",
-                        "message": "Something bad happened when fetching all posts"
-                    }))
-                }
-            }
-        }
-    }
+    Ok(HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "data": posts.value,
+        "source": source(posts.hit)
+    })))
 }
 
 #[get("/detail/{id}")]
 pub async fn get_one_post(
     path:web::Path<i32>,
     data: web::Data<AppState>,
-) -> impl Responder {
-    let id = path.into_inner();
-    let post = query_as!(
-        Post,
-        r#"SELECT * FROM post WHERE id=$1"#,
-        id,
-    ).fetch_one(&data.db).await;
-    match post {
-        Ok(posts) => {
-            let json_response = serde_json::json!({
-                "status": "ok",
-                "data": posts,
-            });
-            HttpResponse::Ok().json(json_response)
-        },
-        Err(err) => {
-            if err.to_string().contains("no rows returned by a query that expected to return at least one row") {
-                return HttpResponse::NotFound().json(
-                    json!({"status":"failed","messsage":"data not found"})
-                )
-            }
-            else {
-            HttpResponse::InternalServerError().json(
-                serde_json::json!({"status": "error", "message": "Something bad happened when fetching all posts"}),
-            )
-            }
-        },
-    }
+) -> Result<HttpResponse, ApiError> {
+    let post = post_cache::get_post(&data, path.into_inner()).await?;
+    let source = source(post.hit);
+    let post = post.value.ok_or(ApiError::NotFound("post not found".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "data": post,
+        "source": source
+    })))
 }
 
-#[post("")]
+#[post("", wrap = "RequirePermission(\"post:create\")", wrap = "Authentication")]
 async fn create_post_handlers(
-    body:web::Json<NewPost>,
+    user:AuthenticatedUser,
+    body:ValidatedJson<NewPost>,
     data:web::Data<AppState>,
-) -> impl Responder {
-    let new_post = query_as!(
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = data.db.begin().await?;
+    let post = query_as!(
         Post,
-        r#"INSERT INTO post(title, content) VALUES ($1, $2) RETURNING *"#,
+        r#"INSERT INTO post(title, content, author_id) VALUES ($1, $2, $3)
+        RETURNING id, title, content, create_at, updated_at, author_id"#,
         body.title,
         body.content,
+        user.id,
     )
-    .fetch_one(&data.db)
-    .await;
-
-    match new_post {
-        Ok(post)=>{
-            let response_json = serde_json::json!({"status":"success","data":serde_json::json!({
-                "post":post
-            })});
-
-            return HttpResponse::Created().json(json!(response_json))
-        }
-        Err(e)=>{
-            if e.to_string()
-            .contains("duplicate key value violates unique constraint")
-                {
-                    return HttpResponse::BadRequest()
-                    .json(serde_json::json!({"status": "failed","message": "Post with that title already exists"}));
-                }
-
-                return HttpResponse::InternalServerError()
-                    .json(serde_json::json!({"status": "error","message": format!("{:?}", e)}));
-                }
-    }
+    .fetch_one(&mut *tx)
+    .await?;
+    outbox::enqueue(&mut tx, POST_CREATED, "post", post.id.to_string(), &post).await?;
+    tx.commit().await?;
+    post_cache::invalidate(&data.cache, post.id).await;
+
+    Ok(HttpResponse::Created().json(json!({"status":"success","data":{
+        "post":post
+    }})))
 }
 
-#[delete("/{id}")]
+#[delete("/{id}", wrap = "RequirePermission(\"post:delete\")", wrap = "Authentication")]
 pub async fn delete_post_by_id(
+    user:AuthenticatedUser,
     id:web::Path<i32>,
     data: web::Data<AppState>,
-) -> impl Responder {
-        let id = id.into_inner();
-        let post = query_as!(
-            Post,
-            r#"SELECT * FROM post WHERE id = $1"#,
-            id,
-        ).fetch_one(&data.db).await;
-    
-        match post {
-            Ok(post) => {
-            query!("DELETE FROM post where id=$1",post.id).execute(&data.db).await.unwrap().rows_affected();
-    
-                let json_response = json!({
-                    "status": "ok",
-                    "message":"post delete success",
-                });
-                HttpResponse::Ok().json(json_response)
-            },
-            Err(err) => {
-                if err.to_string().contains("no rows returned by a query that expected to return at least one row") {
-                    return HttpResponse::NotFound().json(
-                        json!({"status":"failed","messsage":"data not found"})
-                    )
-                }
-                else {
-                HttpResponse::InternalServerError().json(
-                    serde_json::json!({"status": "error", "message": "Something bad happened when fetching all posts"}),
-                )
-                }
-            },
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let mut tx = data.db.begin().await?;
+    let post = query_as!(
+        Post,
+        r#"DELETE FROM post WHERE id = $1 AND (author_id = $2 OR $3)
+        RETURNING id, title, content, create_at, updated_at, author_id"#,
+        id,
+        user.id,
+        user.has_permission("post:moderate"),
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    match post {
+        Some(post) => {
+            outbox::enqueue(&mut tx, POST_DELETED, "post", post.id.to_string(), &post).await?;
+            tx.commit().await?;
+            post_cache::invalidate(&data.cache, post.id).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "ok",
+                "message":"post delete success",
+            })))
+        }
+        // nothing matched: either the post is gone or it belongs to someone else
+        None => {
+            let post = find_post(&data, id).await?;
+            ensure_can_modify(&user, &post)?;
+            Err(ApiError::NotFound("post not found".to_string()))
         }
+    }
 }
 
-#[patch("/{id}")]
+#[patch("/{id}", wrap = "RequirePermission(\"post:update\")", wrap = "Authentication")]
 pub async fn update_post_by_id(
+    user:AuthenticatedUser,
     id:web::Path<i32>,
     data: web::Data<AppState>,
-    body:web::Json<UpdatePost>
-) -> impl Responder {
-        let id = id.into_inner();
-        let post = query_as!(
-            Post,
-            r#"SELECT * FROM post WHERE id = $1"#,
-            id
-        ).fetch_one(&data.db).await;
-        match post {
-            Ok(post) => {
-                let update_post = query_as!(
-                    Post,
-                    r#"UPDATE post SET title=$1,content=$2 RETURNING *"#,
-                    body.title.clone().unwrap_or_else(||post.title),
-                    body.content.clone().unwrap_or_else(||post.content),
-                ).fetch_one(&data.db).await;
-
-                match update_post {
-                    Ok(_)=>{
-                        let response =  json!({
-                            "message":"update success",
-                            "status":"success",
-                            "data":body
-                        });
-                        HttpResponse::Ok().json(response)
-                    },
-                    Err(err)=>{
-                        HttpResponse::InternalServerError().json(
-                            serde_json::json!({"status": "error", "message": format!("{}",err)}),
-                        )
-                    }
-                }
-            },
-            Err(err) => {
-                if err.to_string().contains("no rows returned by a query that expected to return at least one row") {
-                    return HttpResponse::NotFound().json(
-                        json!({"status":"failed","messsage":"data not found"})
-                    )
-                }
-                else {
-                HttpResponse::InternalServerError().json(
-                    serde_json::json!({"status": "error", "message": "Something bad happened when fetching all posts"}),
-                )
-                }
-            },
+    body:ValidatedJson<UpdatePost>
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    // updated_at is bumped by the post_set_updated_at trigger
+    let mut tx = data.db.begin().await?;
+    let post = query_as!(
+        Post,
+        r#"UPDATE post SET title = COALESCE($1, title), content = COALESCE($2, content)
+        WHERE id = $3 AND (author_id = $4 OR $5)
+        RETURNING id, title, content, create_at, updated_at, author_id"#,
+        body.title,
+        body.content,
+        id,
+        user.id,
+        user.has_permission("post:moderate"),
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    match post {
+        Some(post) => {
+            outbox::enqueue(&mut tx, POST_UPDATED, "post", post.id.to_string(), &post).await?;
+            tx.commit().await?;
+            post_cache::invalidate(&data.cache, post.id).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "message":"update success",
+                "status":"success",
+                "data":post
+            })))
+        }
+        // nothing matched: either the post is gone or it belongs to someone else
+        None => {
+            let post = find_post(&data, id).await?;
+            ensure_can_modify(&user, &post)?;
+            Err(ApiError::NotFound("post not found".to_string()))
         }
+    }
+}
+
+async fn find_post(data:&AppState, id:i32) -> Result<Post, ApiError> {
+    query_as!(
+        Post,
+        r#"SELECT id, title, content, create_at, updated_at, author_id FROM post WHERE id = $1"#,
+        id
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("post not found".to_string()))
 }
 
+/// Only the author of a post, or a moderator, may change or remove it.
+fn ensure_can_modify(user:&AuthenticatedUser, post:&Post) -> Result<(), ApiError> {
+    if post.author_id == Some(user.id) || user.has_permission("post:moderate") {
+        return Ok(());
+    }
+    Err(ApiError::Forbidden("only the author or a moderator can modify this post".to_string()))
+}
 
+fn source(hit: bool) -> &'static str {
+    if hit { "cache" } else { "database" }
+}
 
 pub fn public_post_config(conf: &mut web::ServiceConfig) {
     let public_scope = web::scope("/post")
+    .service(list_posts)
     .service(get_all_post)
     .service(get_one_post)
     .service(create_post_handlers)
@@ -237,4 +213,4 @@ pub fn public_post_config(conf: &mut web::ServiceConfig) {
     .service(update_post_by_id);
 
     conf.service(public_scope);
-}
+}
\ No newline at end of file
@@ -1,26 +1,160 @@
-use sqlx::types::chrono::NaiveDateTime;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sqlx::types::chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::FromRow;
+use uuid::Uuid;
 use serde::{Serialize,Deserialize};
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Post {
     pub id: i32,
     pub title: String,
     pub content: String,
-    pub create_at: Option<NaiveDateTime>,
+    pub create_at: NaiveDateTime,
     pub updated_at: Option<NaiveDateTime>,
+    pub author_id: Option<Uuid>,
 }
 
 #[derive(Serialize,Deserialize,Validate)]
 pub struct NewPost{
-    #[validate(length(min="5",message="please add your title"))]
+    #[validate(length(min="5",max="255",message="title must be between 5 and 255 characters"))]
     pub title:String,
     #[validate(length(min="20",message="please add your content"))]
     pub content:String,
 }
 
-#[derive(Serialize,Deserialize)]
+/// Only the fields that are sent are changed, at least one must be.
+#[derive(Serialize,Deserialize,Validate)]
+#[validate(schema(function="validate_update_post"))]
 pub struct UpdatePost{
+    #[validate(length(min="5",max="255",message="title must be between 5 and 255 characters"))]
     pub title:Option<String>,
+    #[validate(length(min="20",message="please add your content"))]
     pub content:Option<String>
-}
\ No newline at end of file
+}
+
+fn validate_update_post(post:&UpdatePost) -> Result<(), ValidationError> {
+    if post.title.is_none() && post.content.is_none() {
+        return Err(ValidationError::new("empty_update").with_message("send a title or content to change".into()));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize,Validate)]
+pub struct PostListQuery{
+    #[validate(range(min=1,max=100,message="limit must be between 1 and 100"))]
+    #[serde(default="default_limit")]
+    pub limit:i64,
+    pub cursor:Option<String>,
+    #[serde(default)]
+    pub include_total:bool,
+    /// Full-text search over title and content, web search syntax
+    /// (`"exact phrase"`, `-excluded`, `or`).
+    #[validate(length(min=1,max=200,message="q must be between 1 and 200 characters"))]
+    pub q:Option<String>,
+    pub author:Option<Uuid>,
+    pub created_after:Option<DateTime<Utc>>,
+    pub created_before:Option<DateTime<Utc>>,
+    /// Defaults to `relevance` when searching and `newest` otherwise.
+    pub sort:Option<PostSort>,
+}
+
+fn default_limit() -> i64 {
+    10
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PostSort {
+    Newest,
+    Oldest,
+    Relevance,
+}
+
+/// A post in a listing. Searches add the rank and highlighted title and
+/// snippet, with matches wrapped in `<mark>` and everything else escaped.
+#[derive(Serialize, FromRow)]
+pub struct PostListItem {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub post: Post,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title_highlight: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+/// The sort key of a cursor, it has to match the sort it is used with.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CursorKey {
+    CreatedAt(NaiveDateTime),
+    Rank(f32),
+}
+
+/// Position after the last post of a page, sent to clients as an opaque
+/// string.
+pub struct PostCursor{
+    pub key:CursorKey,
+    pub id:i32,
+}
+
+impl PostCursor {
+    pub fn encode(&self) -> String {
+        let raw = match self.key {
+            CursorKey::CreatedAt(create_at) => format!("c:{}:{}", create_at.and_utc().timestamp_micros(), self.id),
+            // the exact bits, so the next page starts right after this rank
+            CursorKey::Rank(rank) => format!("r:{}:{}", rank.to_bits(), self.id),
+        };
+        URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    pub fn decode(cursor:&str) -> Option<PostCursor> {
+        let raw = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+        let mut parts = raw.splitn(3, ':');
+        let (kind, key, id) = (parts.next()?, parts.next()?, parts.next()?);
+        let key = match kind {
+            "c" => CursorKey::CreatedAt(DateTime::from_timestamp_micros(key.parse().ok()?)?.naive_utc()),
+            "r" => CursorKey::Rank(f32::from_bits(key.parse().ok()?)),
+            _ => return None,
+        };
+        Some(PostCursor { key, id: id.parse().ok()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created_at() -> NaiveDateTime {
+        DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap().naive_utc()
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        for key in [CursorKey::CreatedAt(created_at()), CursorKey::Rank(0.0759), CursorKey::Rank(f32::MIN_POSITIVE)] {
+            let decoded = PostCursor::decode(&PostCursor { key, id: 42 }.encode()).unwrap();
+            assert!(decoded.key == key);
+            assert_eq!(decoded.id, 42);
+        }
+    }
+
+    #[test]
+    fn garbage_cursor_is_rejected() {
+        for raw in ["", "c:123", "c:abc:1", "c:123:", "c:123:x", "r:notbits:1", "x:1:2", "c:99999999999999999999:1"] {
+            assert!(PostCursor::decode(&URL_SAFE_NO_PAD.encode(raw)).is_none(), "{:?} decoded", raw);
+        }
+        assert!(PostCursor::decode("not base64!").is_none());
+        assert!(PostCursor::decode(&URL_SAFE_NO_PAD.encode([0xff, 0xfe, b':', b'1'])).is_none());
+    }
+
+    #[test]
+    fn truncated_cursor_is_rejected() {
+        let cursor = PostCursor { key: CursorKey::CreatedAt(created_at()), id: 7 }.encode();
+        // cut inside the key, before the id starts
+        for len in 0..8 {
+            assert!(PostCursor::decode(&cursor[..len]).is_none(), "{:?} decoded", &cursor[..len]);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use sqlx::{PgPool, Postgres, QueryBuilder};
+
+use super::post_models::{CursorKey, PostCursor, PostListItem, PostListQuery, PostSort};
+use crate::utils::api_error::ApiError;
+
+const POST_COLUMNS: &str = "post.id, post.title, post.content, post.create_at, post.updated_at, post.author_id";
+
+/// ts_headline markers, swapped for `<mark>` once the text around them is
+/// escaped. Posts may contain them too, so they are stripped from the text
+/// before it is highlighted.
+const MARK_START: char = '\u{1}';
+const MARK_STOP: char = '\u{2}';
+
+pub struct PostPage {
+    pub posts: Vec<PostListItem>,
+    pub next_cursor: Option<String>,
+    pub has_more: bool,
+    pub total: Option<i64>,
+}
+
+/// Filters, sorts and searches posts, `params.limit` at a time. The sort
+/// and filters are picked from a fixed set, user input is only ever bound.
+pub async fn list_posts(db: &PgPool, params: &PostListQuery) -> Result<PostPage, ApiError> {
+    let search = params.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let sort = match (params.sort, search) {
+        (Some(PostSort::Relevance), None) => {
+            return Err(ApiError::BadRequest("sort=relevance needs a search query q".to_string()))
+        }
+        (Some(sort), _) => sort,
+        (None, Some(_)) => PostSort::Relevance,
+        (None, None) => PostSort::Newest,
+    };
+    let cursor = match &params.cursor {
+        Some(cursor) => Some(PostCursor::decode(cursor).ok_or(ApiError::BadRequest("invalid cursor".to_string()))?),
+        None => None,
+    };
+
+    let mut query = QueryBuilder::<Postgres>::new("SELECT ");
+    query.push(POST_COLUMNS);
+    if search.is_some() {
+        let marks = format!("StartSel={}, StopSel={}", MARK_START, MARK_STOP);
+        query
+            .push(", ts_rank(post.search, query) AS rank, ts_headline('english', translate(post.title::text, chr(1) || chr(2), ''), query, ")
+            .push_bind(format!("{}, HighlightAll=true", marks))
+            .push(") AS title_highlight, ts_headline('english', translate(post.content, chr(1) || chr(2), ''), query, ")
+            .push_bind(format!("{}, MaxFragments=2, MaxWords=30, MinWords=10", marks))
+            .push(") AS snippet");
+    } else {
+        query.push(", NULL::real AS rank, NULL::text AS title_highlight, NULL::text AS snippet");
+    }
+    push_filters(&mut query, params, search);
+
+    match (sort, cursor) {
+        (_, None) => {}
+        (PostSort::Newest, Some(PostCursor { key: CursorKey::CreatedAt(create_at), id })) => {
+            query.push(" AND (post.create_at, post.id) < (").push_bind(create_at).push(", ").push_bind(id).push(")");
+        }
+        (PostSort::Oldest, Some(PostCursor { key: CursorKey::CreatedAt(create_at), id })) => {
+            query.push(" AND (post.create_at, post.id) > (").push_bind(create_at).push(", ").push_bind(id).push(")");
+        }
+        (PostSort::Relevance, Some(PostCursor { key: CursorKey::Rank(rank), id })) => {
+            query.push(" AND (ts_rank(post.search, query), post.id) < (").push_bind(rank).push(", ").push_bind(id).push(")");
+        }
+        _ => return Err(ApiError::BadRequest("cursor was issued for a different sort".to_string())),
+    }
+
+    query.push(match sort {
+        PostSort::Newest => " ORDER BY post.create_at DESC, post.id DESC",
+        PostSort::Oldest => " ORDER BY post.create_at, post.id",
+        PostSort::Relevance => " ORDER BY rank DESC, post.id DESC",
+    });
+    // one extra row tells whether another page exists
+    query.push(" LIMIT ").push_bind(params.limit + 1);
+
+    let mut posts: Vec<PostListItem> = query.build_query_as().fetch_all(db).await?;
+    let has_more = posts.len() as i64 > params.limit;
+    posts.truncate(params.limit as usize);
+    for post in posts.iter_mut() {
+        post.title_highlight = post.title_highlight.take().map(|text| highlight(&text));
+        post.snippet = post.snippet.take().map(|text| highlight(&text));
+    }
+
+    let next_cursor = match posts.last() {
+        Some(last) if has_more => {
+            let key = match sort {
+                PostSort::Relevance => CursorKey::Rank(last.rank.unwrap_or_default()),
+                _ => CursorKey::CreatedAt(last.post.create_at),
+            };
+            Some(PostCursor { key, id: last.post.id }.encode())
+        }
+        _ => None,
+    };
+
+    let total = if params.include_total {
+        let mut count = QueryBuilder::<Postgres>::new("SELECT COUNT(*)");
+        push_filters(&mut count, params, search);
+        Some(count.build_query_scalar().fetch_one(db).await?)
+    } else {
+        None
+    };
+
+    Ok(PostPage { posts, next_cursor, has_more, total })
+}
+
+fn push_filters(query: &mut QueryBuilder<'_, Postgres>, params: &PostListQuery, search: Option<&str>) {
+    query.push(" FROM post");
+    match search {
+        Some(search) => {
+            query
+                .push(", websearch_to_tsquery('english', ")
+                .push_bind(search.to_string())
+                .push(") AS query WHERE post.search @@ query");
+        }
+        None => {
+            query.push(" WHERE TRUE");
+        }
+    }
+    if let Some(author) = params.author {
+        query.push(" AND post.author_id = ").push_bind(author);
+    }
+    if let Some(after) = params.created_after {
+        query.push(" AND post.create_at >= ").push_bind(after.naive_utc());
+    }
+    if let Some(before) = params.created_before {
+        query.push(" AND post.create_at < ").push_bind(before.naive_utc());
+    }
+}
+
+/// Escapes a ts_headline result for HTML and turns the markers into `<mark>`.
+fn highlight(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            MARK_START => html.push_str("<mark>"),
+            MARK_STOP => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::chrono::DateTime;
+
+    use super::*;
+
+    fn params(sort: Option<PostSort>, q: Option<&str>, cursor: Option<String>) -> PostListQuery {
+        PostListQuery {
+            limit: 2,
+            cursor,
+            include_total: false,
+            q: q.map(str::to_string),
+            author: None,
+            created_after: None,
+            created_before: None,
+            sort,
+        }
+    }
+
+    async fn insert_posts(db: &PgPool, count: usize) {
+        for n in 0..count {
+            sqlx::query("INSERT INTO post (title, content) VALUES ($1, 'some searchable content for the post')")
+                .bind(format!("post number {}", n))
+                .execute(db)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn cursor_pages_through_every_post(db: PgPool) {
+        insert_posts(&db, 5).await;
+        let mut seen = vec![];
+        let mut cursor = None;
+        loop {
+            let page = list_posts(&db, &params(Some(PostSort::Newest), None, cursor)).await.unwrap();
+            seen.extend(page.posts.iter().map(|post| post.post.id));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        let mut expected = seen.clone();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        expected.dedup();
+        assert_eq!(seen.len(), 5);
+        assert_eq!(seen, expected);
+    }
+
+    #[sqlx::test]
+    async fn rank_cursor_is_rejected_for_newest(db: PgPool) {
+        let cursor = PostCursor { key: CursorKey::Rank(0.5), id: 1 }.encode();
+        let err = list_posts(&db, &params(Some(PostSort::Newest), None, Some(cursor))).await.err().unwrap();
+        assert!(matches!(err, ApiError::BadRequest(message) if message.contains("different sort")));
+    }
+
+    #[sqlx::test]
+    async fn date_cursor_is_rejected_for_relevance(db: PgPool) {
+        let created_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap().naive_utc();
+        let cursor = PostCursor { key: CursorKey::CreatedAt(created_at), id: 1 }.encode();
+        let err = list_posts(&db, &params(Some(PostSort::Relevance), Some("post"), Some(cursor))).await.err().unwrap();
+        assert!(matches!(err, ApiError::BadRequest(message) if message.contains("different sort")));
+    }
+
+    #[sqlx::test]
+    async fn markers_in_posts_are_not_highlights(db: PgPool) {
+        sqlx::query("INSERT INTO post (title, content) VALUES ($1, $2)")
+            .bind("sneaky \u{1}title\u{2} here")
+            .bind("the content \u{1}opens a mark and never closes it, then talks about searching")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let page = list_posts(&db, &params(None, Some("searching"), None)).await.unwrap();
+        let post = &page.posts[0];
+        assert_eq!(post.title_highlight.as_deref(), Some("sneaky title here"));
+        let snippet = post.snippet.as_deref().unwrap();
+        assert_eq!(snippet.matches("<mark>").count(), 1, "{}", snippet);
+        assert_eq!(snippet.matches("</mark>").count(), 1, "{}", snippet);
+        assert!(snippet.contains("<mark>searching</mark>"), "{}", snippet);
+    }
+
+    #[sqlx::test]
+    async fn garbage_cursor_is_a_bad_request(db: PgPool) {
+        let err = list_posts(&db, &params(None, None, Some("garbage".to_string()))).await.err().unwrap();
+        assert!(matches!(err, ApiError::BadRequest(message) if message == "invalid cursor"));
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::{self, time::sleep};
+use futures::future::{select, Either, LocalBoxFuture};
+use futures::StreamExt;
+use lapin::{
+    message::Delivery as AmqpDelivery,
+    options::{
+        BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
+        BasicQosOptions, ConfirmSelectOptions,
+    },
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel,
+};
+use serde::de::DeserializeOwned;
+
+use super::rabbitmq::RabbitMqPool;
+use super::shutdown::{BackgroundTask, ShutdownSignal};
+use super::topology::{Topology, TopologyError};
+use crate::config::settings::{ConsumerSettings, QueueSettings};
+use crate::utils::api_error::ApiError;
+
+/// Header carrying how many times a message has been retried.
+const RETRIES_HEADER: &str = "x-retries";
+
+/// A message decoded for a handler.
+pub struct Delivery<T> {
+    pub message: T,
+    pub message_id: Option<String>,
+    pub routing_key: String,
+    /// 0 on the first delivery, incremented on every retry.
+    pub retries: u32,
+}
+
+#[derive(Debug)]
+pub enum HandlerError {
+    /// Try again later, with backoff, until the retries run out.
+    Retry(String),
+    /// The message can never succeed, dead-letter it right away.
+    Reject(String),
+}
+
+impl From<ApiError> for HandlerError {
+    fn from(err: ApiError) -> Self {
+        HandlerError::Retry(format!("{:?}", err))
+    }
+}
+
+/// Handles the messages of one queue. A message is acked once `handle`
+/// returns `Ok`; it may be delivered more than once, so handlers must be
+/// idempotent.
+pub trait Handler: 'static {
+    type Message: DeserializeOwned;
+
+    fn handle(&self, delivery: Delivery<Self::Message>) -> LocalBoxFuture<'_, Result<(), HandlerError>>;
+}
+
+/// `Handler` with the message type erased, so consumers of different
+/// messages live in one registry.
+trait RawHandler {
+    fn handle_raw<'a>(&'a self, delivery: &'a AmqpDelivery, retries: u32) -> LocalBoxFuture<'a, Result<(), HandlerError>>;
+}
+
+impl<H: Handler> RawHandler for H {
+    fn handle_raw<'a>(&'a self, delivery: &'a AmqpDelivery, retries: u32) -> LocalBoxFuture<'a, Result<(), HandlerError>> {
+        Box::pin(async move {
+            // a payload that does not parse now never will
+            let message = serde_json::from_slice(&delivery.data)
+                .map_err(|e| HandlerError::Reject(format!("invalid payload: {}", e)))?;
+            let delivery = Delivery {
+                message,
+                message_id: delivery.properties.message_id().as_ref().map(|id| id.to_string()),
+                routing_key: delivery.routing_key.to_string(),
+                retries,
+            };
+            self.handle(delivery).await
+        })
+    }
+}
+
+struct Consumer {
+    queue: String,
+    routing_keys: Vec<String>,
+    handler: Box<dyn RawHandler>,
+}
+
+/// Consumers to start with the server, one queue each.
+#[derive(Default)]
+pub struct ConsumerRegistry {
+    consumers: Vec<Consumer>,
+}
+
+impl ConsumerRegistry {
+    /// Consumes `queue`, bound to the events exchange with `routing_keys`.
+    pub fn register<H: Handler>(&mut self, queue: &str, routing_keys: &[&str], handler: H) {
+        self.consumers.push(Consumer {
+            queue: queue.to_string(),
+            routing_keys: routing_keys.iter().map(|key| key.to_string()).collect(),
+            handler: Box::new(handler),
+        });
+    }
+
+    /// Adds the queue of every consumer with its bindings to `topology`,
+    /// plus one delay queue per retry and a dead letter queue.
+    pub fn declare(&self, topology: &mut Topology, exchange: &str, settings: &ConsumerSettings) -> Result<(), TopologyError> {
+        for consumer in &self.consumers {
+            let queue = consumer.queue.as_str();
+            topology.queue(QueueSettings {
+                name: queue.to_string(),
+                durable: true,
+                quorum: settings.quorum,
+                dead_letter_exchange: Some(settings.dead_letter_exchange.clone()),
+                dead_letter_routing_key: Some(queue.to_string()),
+                ..QueueSettings::default()
+            })?;
+            for routing_key in &consumer.routing_keys {
+                topology.bind(queue, exchange, routing_key);
+            }
+
+            let dead_queue = format!("{}.dead", queue);
+            topology.queue(QueueSettings {
+                name: dead_queue.clone(),
+                durable: true,
+                quorum: settings.quorum,
+                ..QueueSettings::default()
+            })?;
+            topology.bind(&dead_queue, &settings.dead_letter_exchange, queue);
+
+            // expired messages are dead-lettered through the default exchange straight back to the queue
+            for retries in 0..settings.max_retries {
+                let ttl = settings.retry_delay_ms.saturating_mul(1 << retries).min(u32::MAX as u64) as u32;
+                topology.queue(QueueSettings {
+                    name: retry_queue(queue, retries),
+                    durable: true,
+                    message_ttl_ms: Some(ttl),
+                    dead_letter_exchange: Some(String::new()),
+                    dead_letter_routing_key: Some(queue.to_string()),
+                    ..QueueSettings::default()
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts every consumer on the current runtime. They finish the message
+    /// in hand, cancel and return once `shutdown` fires.
+    pub fn spawn(
+        self,
+        rabbit: &RabbitMqPool,
+        topology: &Arc<Topology>,
+        settings: &ConsumerSettings,
+        shutdown: ShutdownSignal,
+    ) -> Vec<BackgroundTask> {
+        self.consumers
+            .into_iter()
+            .map(|consumer| {
+                let name = format!("consumer {}", consumer.queue);
+                let task = rt::spawn(run_consumer(
+                    consumer,
+                    rabbit.clone(),
+                    topology.clone(),
+                    settings.clone(),
+                    shutdown.clone(),
+                ));
+                (name, task)
+            })
+            .collect()
+    }
+}
+
+/// Keeps one consumer running, reconnecting with backoff when the
+/// connection or channel is lost.
+async fn run_consumer(
+    consumer: Consumer,
+    rabbit: RabbitMqPool,
+    topology: Arc<Topology>,
+    settings: ConsumerSettings,
+    shutdown: ShutdownSignal,
+) {
+    let mut failures = 0;
+    loop {
+        match consume(&consumer, &rabbit, &topology, &settings, &shutdown, &mut failures).await {
+            Ok(()) => break,
+            Err(err) => {
+                failures += 1;
+                let delay = Duration::from_secs(2u64.pow(failures.min(6)));
+                log::warn!("consumer {} failed, restarting in {:?}: {:?}", consumer.queue, delay, err);
+                if let Either::Right(_) = select(Box::pin(sleep(delay)), shutdown.clone()).await {
+                    break;
+                }
+            }
+        }
+    }
+    log::info!("consumer {} stopped", consumer.queue);
+}
+
+/// Consumes until shutdown, which returns `Ok`, or until the channel fails.
+async fn consume(
+    consumer: &Consumer,
+    rabbit: &RabbitMqPool,
+    topology: &Topology,
+    settings: &ConsumerSettings,
+    shutdown: &ShutdownSignal,
+    failures: &mut u32,
+) -> Result<(), ApiError> {
+    topology.ensure(rabbit).await?;
+    let conn = rabbit.get().await?;
+    let channel = conn.create_channel().await?;
+    channel.basic_qos(settings.prefetch, BasicQosOptions::default()).await?;
+    // retries are republished, the original is only acked once the copy is confirmed
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+
+    let tag = format!("{}-{}", consumer.queue, uuid::Uuid::new_v4());
+    let mut deliveries = channel
+        .basic_consume(&consumer.queue, &tag, BasicConsumeOptions::default(), FieldTable::default())
+        .await?;
+    *failures = 0;
+    log::info!("consumer {} started", consumer.queue);
+
+    loop {
+        let next = match select(deliveries.next(), shutdown.clone()).await {
+            Either::Left((next, _)) => next,
+            Either::Right(_) => break,
+        };
+        let delivery = match next {
+            Some(delivery) => delivery?,
+            None => return Err(ApiError::RabbitMq(format!("consumer {} was cancelled", consumer.queue))),
+        };
+        process(&channel, consumer, settings, &delivery).await?;
+    }
+
+    // unacked prefetched messages go back to the queue when the channel closes
+    channel.basic_cancel(&tag, BasicCancelOptions::default()).await?;
+    channel.close(200, "shutting down").await?;
+    Ok(())
+}
+
+async fn process(
+    channel: &Channel,
+    consumer: &Consumer,
+    settings: &ConsumerSettings,
+    delivery: &AmqpDelivery,
+) -> Result<(), ApiError> {
+    let retries = retries(&delivery.properties);
+    match consumer.handler.handle_raw(delivery, retries).await {
+        Ok(()) => delivery.ack(BasicAckOptions::default()).await?,
+        Err(HandlerError::Retry(reason)) if retries < settings.max_retries => {
+            log::warn!("{} failed (retry {}), retrying: {}", consumer.queue, retries + 1, reason);
+            let mut headers = delivery.properties.headers().clone().unwrap_or_default();
+            headers.insert(RETRIES_HEADER.into(), AMQPValue::LongUInt(retries + 1));
+            let properties = delivery.properties.clone().with_headers(headers);
+
+            let confirm = channel
+                .basic_publish(
+                    "",
+                    &retry_queue(&consumer.queue, retries),
+                    BasicPublishOptions::default(),
+                    &delivery.data,
+                    properties,
+                )
+                .await?
+                .await?;
+            if confirm.is_nack() {
+                // not retried yet, hand it back as is and try again
+                delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await?;
+                return Err(ApiError::RabbitMq(format!("broker rejected retry of {}", consumer.queue)));
+            }
+            delivery.ack(BasicAckOptions::default()).await?;
+        }
+        Err(HandlerError::Retry(reason) | HandlerError::Reject(reason)) => {
+            log::error!("{} failed after {} retries, dead-lettering: {}", consumer.queue, retries, reason);
+            // the queue dead-letters rejected messages to the dead letter exchange
+            delivery.nack(BasicNackOptions { requeue: false, ..BasicNackOptions::default() }).await?;
+        }
+    }
+    Ok(())
+}
+
+fn retries(properties: &BasicProperties) -> u32 {
+    match properties.headers().as_ref().and_then(|headers| headers.inner().get(RETRIES_HEADER)) {
+        Some(AMQPValue::LongUInt(retries)) => *retries,
+        _ => 0,
+    }
+}
+
+/// Delay queue the `retries`th retry waits in before going back to `queue`.
+fn retry_queue(queue: &str, retries: u32) -> String {
+    format!("{}.retry.{}", queue, retries + 1)
+}
@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::time::timeout;
+use futures::future::{join_all, LocalBoxFuture};
+use redis::aio::ConnectionLike;
+use serde::Serialize;
+
+use super::rabbitmq::RabbitMqPool;
+use super::redis::RedisConnection;
+
+/// A dependency probed by `/readyz`. Checks must not change any state, they
+/// run on every probe.
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn check(&self) -> LocalBoxFuture<'_, Result<(), String>>;
+}
+
+struct Registered {
+    check: Box<dyn HealthCheck>,
+    timeout: Duration,
+    required: bool,
+}
+
+/// Every check `/readyz` runs. Services register their own at startup.
+#[derive(Default)]
+pub struct HealthRegistry {
+    checks: Vec<Registered>,
+    shutting_down: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct ComponentHealth {
+    pub name: &'static str,
+    pub status: &'static str,
+    pub required: bool,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    /// `up`, `degraded` when only optional components are down, `down`, or
+    /// `shutting_down` once the process stopped taking traffic.
+    pub status: &'static str,
+    pub components: Vec<ComponentHealth>,
+}
+
+impl HealthReport {
+    pub fn is_ready(&self) -> bool {
+        self.status == "up" || self.status == "degraded"
+    }
+}
+
+impl HealthRegistry {
+    /// A `required` component being down fails the whole probe, an optional
+    /// one only marks it degraded.
+    pub fn register(&mut self, check: impl HealthCheck + 'static, timeout: Duration, required: bool) {
+        self.checks.push(Registered { check: Box::new(check), timeout, required });
+    }
+
+    /// Fails every probe from now on, so load balancers stop routing here
+    /// while in-flight requests drain.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Runs every check concurrently, each bounded by its own timeout.
+    pub async fn run(&self) -> HealthReport {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return HealthReport { status: "shutting_down", components: Vec::new() };
+        }
+
+        let components = join_all(self.checks.iter().map(|registered| async move {
+            let started = Instant::now();
+            let result = match timeout(registered.timeout, registered.check.check()).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("timed out after {}ms", registered.timeout.as_millis())),
+            };
+            ComponentHealth {
+                name: registered.check.name(),
+                status: if result.is_ok() { "up" } else { "down" },
+                required: registered.required,
+                latency_ms: started.elapsed().as_millis(),
+                error: result.err(),
+            }
+        }))
+        .await;
+
+        let down = |required: bool| components.iter().any(|c| c.required == required && c.error.is_some());
+        let status = if down(true) {
+            "down"
+        } else if down(false) {
+            "degraded"
+        } else {
+            "up"
+        };
+        HealthReport { status, components }
+    }
+}
+
+pub struct PostgresCheck(pub sqlx::PgPool);
+
+impl HealthCheck for PostgresCheck {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn check(&self) -> LocalBoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            sqlx::query("SELECT 1").execute(&self.0).await.map(|_| ()).map_err(|e| e.to_string())
+        })
+    }
+}
+
+pub struct RedisCheck(pub RedisConnection);
+
+impl HealthCheck for RedisCheck {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn check(&self) -> LocalBoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let mut conn = self.0.clone();
+            conn.req_packed_command(&redis::cmd("PING")).await.map(|_| ()).map_err(|e| e.to_string())
+        })
+    }
+}
+
+pub struct RabbitMqCheck(pub RabbitMqPool);
+
+impl HealthCheck for RabbitMqCheck {
+    fn name(&self) -> &'static str {
+        "rabbitmq"
+    }
+
+    fn check(&self) -> LocalBoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let conn = self.0.get().await.map_err(|e| e.to_string())?;
+            if conn.status().connected() {
+                Ok(())
+            } else {
+                Err(format!("connection is {:?}", conn.status().state()))
+            }
+        })
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use actix_web::rt::task::spawn_blocking;
+use futures::future::LocalBoxFuture;
+use kafka::producer::{Producer, Record, RequiredAcks};
+
+use super::events::{Event, EventPublisher};
+use crate::config::settings::KafkaSettings;
+use crate::utils::api_error::ApiError;
+
+/// Publishes events to one topic, keyed by aggregate id. The default
+/// partitioner hashes the key, so every event of an aggregate lands on the
+/// same partition and is consumed in order.
+pub struct KafkaPublisher {
+    settings: KafkaSettings,
+    // the client blocks, it is only used from the blocking pool
+    producer: Arc<Mutex<Option<Producer>>>,
+}
+
+impl KafkaPublisher {
+    pub fn new(settings: KafkaSettings) -> KafkaPublisher {
+        KafkaPublisher { settings, producer: Arc::new(Mutex::new(None)) }
+    }
+}
+
+impl EventPublisher for KafkaPublisher {
+    fn backend(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn publish<'a>(&'a self, event: &'a Event) -> LocalBoxFuture<'a, Result<(), ApiError>> {
+        Box::pin(async move {
+            let value = serde_json::to_vec(event)?;
+            let key = event.aggregate_id.clone();
+            let producer = self.producer.clone();
+            let settings = self.settings.clone();
+
+            spawn_blocking(move || {
+                let mut producer = producer.lock().unwrap_or_else(PoisonError::into_inner);
+                // connect on first use, so the server starts while kafka is down
+                let connected = match producer.as_mut() {
+                    Some(connected) => connected,
+                    None => producer.insert(
+                        Producer::from_hosts(settings.brokers)
+                            .with_ack_timeout(Duration::from_millis(settings.ack_timeout_ms))
+                            .with_required_acks(RequiredAcks::All)
+                            .create()?,
+                    ),
+                };
+
+                let sent = connected.send(&Record::from_key_value(&settings.topic, key.as_bytes(), value.as_slice()));
+                if sent.is_err() {
+                    // metadata may be stale, reconnect on the next event
+                    *producer = None;
+                }
+                sent.map_err(ApiError::from)
+            })
+            .await
+            .map_err(|e| ApiError::Internal(format!("kafka producer panicked: {}", e)))?
+        })
+    }
+}
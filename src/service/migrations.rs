@@ -0,0 +1,64 @@
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::PgPool;
+
+/// Every file in `migrations/`, embedded at compile time.
+///
+/// The migrator holds a Postgres advisory lock while it runs, so replicas
+/// starting together apply each migration once and the rest wait.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+    /// The file changed after it was applied.
+    pub modified: bool,
+}
+
+pub async fn up(pool: &PgPool) -> Result<(), MigrateError> {
+    MIGRATOR.run(pool).await
+}
+
+/// Reverts migrations newer than `target`, only the latest one when no
+/// target is given. Returns the version the schema is at afterwards.
+pub async fn down(pool: &PgPool, target: Option<i64>) -> Result<i64, MigrateError> {
+    let target = match target {
+        Some(target) => target,
+        None => {
+            let mut applied: Vec<i64> = applied(pool).await?.into_iter().map(|(version, _)| version).collect();
+            applied.sort_unstable();
+            applied.pop();
+            applied.pop().unwrap_or(0)
+        }
+    };
+    MIGRATOR.undo(pool, target).await?;
+    Ok(target)
+}
+
+pub async fn status(pool: &PgPool) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let applied = applied(pool).await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .map(|migration| {
+            let checksum = applied.iter().find(|(version, _)| *version == migration.version).map(|(_, sum)| sum);
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                applied: checksum.is_some(),
+                modified: checksum.is_some_and(|sum| *sum != migration.checksum.as_ref()),
+            }
+        })
+        .collect())
+}
+
+async fn applied(pool: &PgPool) -> Result<Vec<(i64, Vec<u8>)>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    Ok(conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum.into_owned()))
+        .collect())
+}
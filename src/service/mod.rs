@@ -1,2 +1,12 @@
 pub mod redis;
-pub mod rabbitmq;
\ No newline at end of file
+pub mod rabbitmq;
+pub mod token_store;
+pub mod health;
+pub mod shutdown;
+pub mod migrations;
+pub mod outbox;
+pub mod consumer;
+pub mod topology;
+pub mod events;
+pub mod kafka;
+pub mod scheduler;
\ No newline at end of file
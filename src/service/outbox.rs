@@ -0,0 +1,237 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use futures::future::{select, Either};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+
+use super::events::{Event, EventPublisher};
+use super::shutdown::ShutdownSignal;
+use crate::config::settings::OutboxSettings;
+use crate::utils::api_error::ApiError;
+
+pub const POST_CREATED: &str = "post.created";
+pub const POST_UPDATED: &str = "post.updated";
+pub const POST_DELETED: &str = "post.deleted";
+pub const USER_REGISTERED: &str = "user.registered";
+
+/// Records an event. Pass the transaction of the write it describes, so
+/// the event exists if and only if the write commits.
+pub async fn enqueue(
+    conn: &mut PgConnection,
+    event_type: &str,
+    aggregate_type: &str,
+    aggregate_id: String,
+    payload: &impl Serialize,
+) -> Result<(), ApiError> {
+    sqlx::query!(
+        r#"INSERT INTO outbox (event_type, aggregate_type, aggregate_id, payload) VALUES ($1, $2, $3, $4)"#,
+        event_type,
+        aggregate_type,
+        aggregate_id,
+        serde_json::to_value(payload)?,
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Deletes events published more than `retention_hours` ago, returns how
+/// many were deleted. Unpublished events are kept however old they are.
+pub async fn prune(db: &PgPool, retention_hours: i64) -> Result<u64, ApiError> {
+    let result = sqlx::query!(
+        "DELETE FROM outbox WHERE published_at < NOW() - make_interval(hours => $1::int)",
+        retention_hours as i32,
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Publishes pending events until `shutdown` fires, then finishes the batch
+/// in flight and returns.
+pub async fn run_relay(
+    db: PgPool,
+    publisher: Arc<dyn EventPublisher>,
+    settings: OutboxSettings,
+    shutdown: ShutdownSignal,
+) {
+    let interval = Duration::from_millis(settings.poll_interval_ms);
+    loop {
+        let published = match relay_batch(&db, publisher.as_ref(), &settings).await {
+            Ok(published) => published,
+            Err(err) => {
+                log::warn!("outbox relay failed: {:?}", err);
+                0
+            }
+        };
+
+        // publishing an event may have unblocked the next one of its aggregate, go again right away
+        let wait = if published > 0 { Duration::ZERO } else { interval };
+        if let Either::Right(_) = select(Box::pin(sleep(wait)), shutdown.clone()).await {
+            break;
+        }
+    }
+    log::info!("outbox relay stopped");
+}
+
+/// Claims a batch of due events and publishes them. Only the oldest
+/// unpublished event of each aggregate is claimed, so events of one
+/// aggregate go out in order even when one is waiting for a retry. Rows stay
+/// locked until the batch commits; relays on other replicas skip them and,
+/// as they are still unpublished, every later event of their aggregates.
+async fn relay_batch(
+    db: &PgPool,
+    publisher: &dyn EventPublisher,
+    settings: &OutboxSettings,
+) -> Result<usize, ApiError> {
+    let mut tx = db.begin().await?;
+    let events = sqlx::query_as!(
+        Event,
+        r#"SELECT id, event_type, aggregate_type, aggregate_id, created_at AS occurred_at, payload AS data FROM outbox
+        WHERE published_at IS NULL AND next_attempt_at <= NOW()
+        AND NOT EXISTS (
+            SELECT 1 FROM outbox earlier
+            WHERE earlier.aggregate_type = outbox.aggregate_type
+            AND earlier.aggregate_id = outbox.aggregate_id
+            AND earlier.published_at IS NULL
+            AND earlier.seq < outbox.seq
+        )
+        ORDER BY seq
+        LIMIT $1
+        FOR UPDATE SKIP LOCKED"#,
+        settings.batch_size,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if events.is_empty() {
+        return Ok(0);
+    }
+
+    let mut published = 0;
+    for event in &events {
+        match publisher.publish(event).await {
+            Ok(()) => {
+                sqlx::query!(
+                    "UPDATE outbox SET published_at = NOW(), attempts = attempts + 1, last_error = NULL WHERE id = $1",
+                    event.id
+                )
+                .execute(&mut *tx)
+                .await?;
+                published += 1;
+            }
+            Err(err) => {
+                sqlx::query!(
+                    r#"UPDATE outbox SET attempts = attempts + 1, last_error = $2,
+                    next_attempt_at = NOW() + make_interval(secs => LEAST(power(2, attempts), $3::float8))
+                    WHERE id = $1"#,
+                    event.id,
+                    format!("{:?}", err),
+                    settings.max_backoff_secs as f64,
+                )
+                .execute(&mut *tx)
+                .await?;
+                // later events of this aggregate wait for this one, other aggregates go on
+                log::warn!("failed to publish outbox event {}: {:?}", event.id, err);
+            }
+        }
+    }
+    tx.commit().await?;
+    Ok(published)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    use futures::future::LocalBoxFuture;
+    use serde_json::json;
+
+    use super::*;
+    use crate::service::events::InMemoryPublisher;
+
+    fn settings() -> OutboxSettings {
+        OutboxSettings { poll_interval_ms: 1000, batch_size: 100, max_backoff_secs: 300 }
+    }
+
+    async fn enqueue_all(db: &PgPool, events: &[(&str, &str)]) {
+        let mut tx = db.begin().await.unwrap();
+        for (event_type, post_id) in events {
+            enqueue(&mut tx, event_type, "post", post_id.to_string(), &json!({ "id": post_id })).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+    }
+
+    fn published(publisher: &InMemoryPublisher) -> Vec<(String, String)> {
+        publisher
+            .published()
+            .into_iter()
+            .map(|event| (event.event_type, event.aggregate_id))
+            .collect()
+    }
+
+    fn pairs(events: &[(&str, &str)]) -> Vec<(String, String)> {
+        events.iter().map(|(event_type, id)| (event_type.to_string(), id.to_string())).collect()
+    }
+
+    /// Fails the events of the listed aggregates until they are cleared.
+    #[derive(Default)]
+    struct FlakyPublisher {
+        failing: Mutex<HashSet<String>>,
+        inner: InMemoryPublisher,
+    }
+
+    impl EventPublisher for FlakyPublisher {
+        fn backend(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn publish<'a>(&'a self, event: &'a Event) -> LocalBoxFuture<'a, Result<(), ApiError>> {
+            if self.failing.lock().unwrap().contains(&event.aggregate_id) {
+                return Box::pin(async { Err(ApiError::RabbitMq("broker down".to_string())) });
+            }
+            self.inner.publish(event)
+        }
+    }
+
+    #[sqlx::test]
+    async fn publishes_events_of_an_aggregate_in_order(db: PgPool) {
+        enqueue_all(&db, &[(POST_CREATED, "1"), (POST_CREATED, "2"), (POST_UPDATED, "1"), (POST_DELETED, "1")]).await;
+        let publisher = InMemoryPublisher::default();
+
+        // one event per aggregate and batch
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 2);
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 1);
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 1);
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 0);
+
+        assert_eq!(
+            published(&publisher),
+            pairs(&[(POST_CREATED, "1"), (POST_CREATED, "2"), (POST_UPDATED, "1"), (POST_DELETED, "1")])
+        );
+    }
+
+    #[sqlx::test]
+    async fn failed_event_holds_back_its_aggregate_only(db: PgPool) {
+        enqueue_all(&db, &[(POST_CREATED, "1"), (POST_CREATED, "2"), (POST_UPDATED, "1"), (POST_UPDATED, "2")]).await;
+        let publisher = FlakyPublisher::default();
+        publisher.failing.lock().unwrap().insert("1".to_string());
+
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 1);
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 1);
+        // post 1 waits for its backoff, its update must not overtake the create
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 0);
+        assert_eq!(published(&publisher.inner), pairs(&[(POST_CREATED, "2"), (POST_UPDATED, "2")]));
+
+        publisher.failing.lock().unwrap().clear();
+        sqlx::query("UPDATE outbox SET next_attempt_at = NOW()").execute(&db).await.unwrap();
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 1);
+        assert_eq!(relay_batch(&db, &publisher, &settings()).await.unwrap(), 1);
+        assert_eq!(
+            published(&publisher.inner),
+            pairs(&[(POST_CREATED, "2"), (POST_UPDATED, "2"), (POST_CREATED, "1"), (POST_UPDATED, "1")])
+        );
+    }
+}
@@ -1,27 +1,111 @@
-use deadpool_lapin::{Config, Manager, Pool, Timeouts};
-use lapin::ConnectionProperties;
-use std::{env, time::Duration};
+use deadpool_lapin::{Manager, Object, Pool, Timeouts};
+use futures::future::LocalBoxFuture;
+use lapin::{
+    options::{BasicPublishOptions, ConfirmSelectOptions},
+    BasicProperties, Channel, ConnectionProperties,
+};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use crate::config::settings::RabbitMqSettings;
+use crate::utils::api_error::ApiError;
+use super::events::{Event, EventPublisher};
+use super::topology::Topology;
 
 pub type RabbitMqPool = Pool;
 
-pub fn rabbit_connect() -> RabbitMqPool {
-    let rabbitmq_url = env::var("RABBITMQ_URL").expect("RABBITMQ_URL must be set");
-
-    let config = Config {
-        url: Some(rabbitmq_url),
-        ..Default::default()
-    };
-
-    let manager = Manager::new(config.url.unwrap(),ConnectionProperties::default());
+pub fn rabbit_connect(settings: &RabbitMqSettings) -> RabbitMqPool {
+    let manager = Manager::new(settings.url.clone(),ConnectionProperties::default());
+    let timeout = Some(Duration::from_secs(settings.timeout_secs));
 
     Pool::builder(manager)
-    .max_size(15)
+    .max_size(settings.pool_size)
     .timeouts(Timeouts{
-        wait:Some(Duration::from_secs(60)),
-        create:Some(Duration::from_secs(60)),
-        recycle:Some(Duration::from_secs(60))
+        wait:timeout,
+        create:timeout,
+        recycle:timeout
     })
     .runtime(deadpool_lapin::Runtime::Tokio1)
     .build()
     .expect("failed to create rabbit pool")
-}   
+}
+
+/// Closes the pool and every idle connection in it. Closing a connection
+/// waits for the broker to acknowledge it, so frames still buffered on its
+/// channels are sent first. Returns how many connections were closed.
+pub async fn rabbit_close(pool: &RabbitMqPool) -> usize {
+    let no_wait = Timeouts { wait: Some(Duration::ZERO), create: None, recycle: None };
+    let mut closed = 0;
+    for _ in 0..pool.status().available {
+        let Ok(conn) = pool.timeout_get(&no_wait).await else {
+            break;
+        };
+        let conn = Object::take(conn);
+        match conn.close(200, "shutting down").await {
+            Ok(()) => closed += 1,
+            Err(err) => log::warn!("failed to close rabbitmq connection: {}", err),
+        }
+    }
+    pool.close();
+    closed
+}
+
+/// Publishes events to the topic exchange, routed by event type, over one
+/// channel in confirm mode that is reopened when it breaks.
+pub struct RabbitMqPublisher {
+    rabbit: RabbitMqPool,
+    topology: Arc<Topology>,
+    exchange: String,
+    channel: Mutex<Option<Channel>>,
+}
+
+impl RabbitMqPublisher {
+    pub fn new(rabbit: RabbitMqPool, topology: Arc<Topology>, exchange: String) -> RabbitMqPublisher {
+        RabbitMqPublisher { rabbit, topology, exchange, channel: Mutex::new(None) }
+    }
+
+    async fn channel(&self) -> Result<Channel, ApiError> {
+        let open = self.channel.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        if let Some(channel) = open.filter(|channel| channel.status().connected()) {
+            return Ok(channel);
+        }
+
+        // queues bound to the exchange must exist first, or the broker drops events nobody is bound for yet
+        self.topology.ensure(&self.rabbit).await?;
+        let conn = self.rabbit.get().await?;
+        let channel = conn.create_channel().await?;
+        channel.confirm_select(ConfirmSelectOptions::default()).await?;
+        *self.channel.lock().unwrap_or_else(PoisonError::into_inner) = Some(channel.clone());
+        Ok(channel)
+    }
+}
+
+impl EventPublisher for RabbitMqPublisher {
+    fn backend(&self) -> &'static str {
+        "rabbitmq"
+    }
+
+    /// The event id is the message id, so consumers can drop the duplicates
+    /// a retry after a lost confirm produces.
+    fn publish<'a>(&'a self, event: &'a Event) -> LocalBoxFuture<'a, Result<(), ApiError>> {
+        Box::pin(async move {
+            let channel = self.channel().await?;
+            let body = serde_json::to_vec(event)?;
+            let properties = BasicProperties::default()
+                .with_message_id(event.id.to_string().into())
+                .with_type(event.event_type.clone().into())
+                .with_content_type("application/json".into())
+                .with_timestamp(event.occurred_at.and_utc().timestamp() as u64)
+                // persistent, survives a broker restart once queued
+                .with_delivery_mode(2);
+
+            let confirm = channel
+                .basic_publish(&self.exchange, &event.event_type, BasicPublishOptions::default(), &body, properties)
+                .await?
+                .await?;
+            if confirm.is_nack() {
+                return Err(ApiError::RabbitMq(format!("broker rejected event {}", event.id)));
+            }
+            Ok(())
+        })
+    }
+}
@@ -1,19 +1,194 @@
-use std::env;
-use r2d2_redis::{r2d2::Pool, RedisConnectionManager};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-pub type RedisPool = Pool<RedisConnectionManager>;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::{AsyncCommands, RedisError};
+use serde::{de::DeserializeOwned, Serialize};
 
-pub fn redis_connect() -> RedisPool{
-    let redis_hostname=env::var("REDIS_HOSTNAME").expect("hostname empty please fill");
-    let redis_password = env::var("REDIS_PASSWORD").unwrap_or_default();
+use crate::config::settings::RedisSettings;
+use crate::utils::api_error::ApiError;
 
-    let conn_url = format!("redis://{}@{}",redis_password,redis_hostname);
+/// One multiplexed connection, cloning it is cheap and every clone shares
+/// the same socket. Reconnects on its own after the server goes away.
+pub type RedisConnection = ConnectionManager;
 
-    let manager: RedisConnectionManager = RedisConnectionManager::new(conn_url).expect("Invalid connection URL");
+pub async fn redis_connect(settings: &RedisSettings) -> Result<RedisConnection, RedisError> {
+    let conn_url = format!("redis://{}@{}",settings.password,settings.hostname);
+    let client = redis::Client::open(conn_url)?;
 
-    Pool::builder()
-        .min_idle(Some(5))
-        .max_size(50) 
-        .build(manager)
-        .expect("Failed to create Redis connection pool")
-}
\ No newline at end of file
+    let config = ConnectionManagerConfig::new()
+        .set_connection_timeout(Duration::from_millis(settings.connect_timeout_ms))
+        .set_response_timeout(Duration::from_millis(settings.response_timeout_ms))
+        .set_number_of_retries(settings.reconnect_retries);
+    ConnectionManager::new_with_config(client, config).await
+}
+
+/// A value returned by [`Cache::get_or_load`] and whether Redis had it.
+pub struct Cached<T> {
+    pub value: T,
+    pub hit: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub errors: u64,
+}
+
+/// One in-flight load. The first caller fills it, callers queued on the
+/// same key read the result instead of running the loader again.
+type Slot = Arc<futures::lock::Mutex<Option<String>>>;
+
+/// Read-through JSON cache over the Redis connection. Cheap to clone, every clone
+/// shares the in-flight loads and the counters, so build it once and hand a
+/// clone to every worker.
+///
+/// Redis is never required: when it fails the loader result is served as is
+/// and the failure is only logged.
+#[derive(Clone)]
+pub struct Cache {
+    conn: RedisConnection,
+    inflight: Arc<Mutex<HashMap<String, Slot>>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    errors: Arc<AtomicU64>,
+}
+
+impl Cache {
+    pub fn new(conn: RedisConnection) -> Cache {
+        Cache {
+            conn,
+            inflight: Arc::default(),
+            hits: Arc::default(),
+            misses: Arc::default(),
+            errors: Arc::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the value under `key`, or runs `loader` and caches its result
+    /// for `ttl` seconds plus up to 10% jitter.
+    pub async fn get_or_load<T, F, Fut>(&self, key: &str, ttl: u64, loader: F) -> Result<Cached<T>, ApiError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        self.load(key, |_| ttl, loader).await
+    }
+
+    /// Like [`Cache::get_or_load`], but a `None` from the loader is cached too,
+    /// for `negative_ttl` seconds, so lookups of missing rows stay cheap.
+    pub async fn get_or_load_optional<T, F, Fut>(
+        &self,
+        key: &str,
+        ttl: u64,
+        negative_ttl: u64,
+        loader: F,
+    ) -> Result<Cached<Option<T>>, ApiError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<T>, ApiError>>,
+    {
+        self.load(key, |value| if value.is_some() { ttl } else { negative_ttl }, loader).await
+    }
+
+    async fn load<T, F, Fut>(&self, key: &str, ttl: impl Fn(&T) -> u64, loader: F) -> Result<Cached<T>, ApiError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        if let Some(value) = self.read(key).await {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Cached { value, hit: true });
+        }
+
+        let slot = self.slot(key);
+        let mut loaded = slot.lock().await;
+        // someone else ran the loader while we were waiting
+        if let Some(value) = loaded.as_deref().and_then(|json| serde_json::from_str(json).ok()) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Cached { value, hit: true });
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = loader().await;
+        if let Ok(value) = &result {
+            let json = serde_json::to_string(value)?;
+            self.write(key, &json, ttl(value)).await;
+            *loaded = Some(json);
+        }
+        drop(loaded);
+        self.release(key, &slot);
+
+        result.map(|value| Cached { value, hit: false })
+    }
+
+    /// Deletes `keys`. Failures are logged only, callers have already
+    /// committed their write by the time they invalidate.
+    pub async fn invalidate(&self, keys: &[String]) {
+        let result = self.conn.clone().del::<_, i64>(keys).await;
+        self.report(result);
+    }
+
+    /// Current value of a counter, `0` when it is missing or Redis is down.
+    pub async fn counter(&self, key: &str) -> i64 {
+        let result = self.conn.clone().get::<_, Option<i64>>(key).await;
+        self.report(result).flatten().unwrap_or(0)
+    }
+
+    pub async fn bump(&self, key: &str) {
+        let result = self.conn.clone().incr::<_, _, i64>(key, 1).await;
+        self.report(result);
+    }
+
+    async fn read<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let result = self.conn.clone().get::<_, Option<String>>(key).await;
+        // an entry that no longer deserializes is treated as a miss
+        self.report(result).flatten().and_then(|json| serde_json::from_str(&json).ok())
+    }
+
+    async fn write(&self, key: &str, json: &str, ttl: u64) {
+        // spread expiry so entries written together do not all miss together
+        let ttl = ttl + OsRng.next_u64() % (ttl / 10 + 1);
+        let result = self.conn.clone().set_ex::<_, _, ()>(key, json, ttl).await;
+        self.report(result);
+    }
+
+    fn report<T>(&self, result: Result<T, RedisError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                log::warn!("cache unavailable: {:?}", err);
+                None
+            }
+        }
+    }
+
+    fn slot(&self, key: &str) -> Slot {
+        let mut inflight = self.inflight.lock().unwrap_or_else(|e| e.into_inner());
+        inflight.entry(key.to_string()).or_default().clone()
+    }
+
+    fn release(&self, key: &str, slot: &Slot) {
+        let mut inflight = self.inflight.lock().unwrap_or_else(|e| e.into_inner());
+        if inflight.get(key).is_some_and(|current| Arc::ptr_eq(current, slot)) {
+            inflight.remove(key);
+        }
+    }
+}
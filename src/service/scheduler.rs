@@ -0,0 +1,131 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use actix_web::rt::{self, time::sleep};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use futures::future::{select, Either, LocalBoxFuture};
+use sqlx::PgPool;
+
+use super::shutdown::{BackgroundTask, ShutdownSignal};
+use crate::config::settings::JobsSettings;
+use crate::utils::api_error::ApiError;
+
+/// First key of every job advisory lock, the second is the hashed job name.
+const LOCK_NAMESPACE: i32 = 0x6a6f62;
+
+type JobFn = Box<dyn Fn() -> LocalBoxFuture<'static, Result<String, ApiError>>>;
+
+struct Job {
+    name: &'static str,
+    schedule: Schedule,
+    run: JobFn,
+}
+
+/// Recurring jobs run inside the server process. Every replica schedules
+/// every job; a Postgres advisory lock and the `job_runs` row of the tick
+/// make sure only one of them runs it.
+pub struct Scheduler {
+    db: PgPool,
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    pub fn new(db: PgPool) -> Scheduler {
+        Scheduler { db, jobs: vec![] }
+    }
+
+    /// Runs `run` on the cron `expression`, see `JobsSettings::schedule`.
+    /// The string it returns is recorded with the run.
+    pub fn register<F, Fut>(&mut self, name: &'static str, expression: &str, run: F)
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = Result<String, ApiError>> + 'static,
+    {
+        match JobsSettings::schedule(expression) {
+            Ok(Some(schedule)) => self.jobs.push(Job { name, schedule, run: Box::new(move || Box::pin(run())) }),
+            Ok(None) => log::info!("job {} is off", name),
+            // rejected when the settings are loaded
+            Err(err) => log::error!("job {} has an invalid schedule: {}", name, err),
+        }
+    }
+
+    /// Starts every job on the current runtime. A running job finishes
+    /// before its task returns once `shutdown` fires.
+    pub fn spawn(self, shutdown: ShutdownSignal) -> Vec<BackgroundTask> {
+        self.jobs
+            .into_iter()
+            .map(|job| (format!("job {}", job.name), rt::spawn(run_job(self.db.clone(), job, shutdown.clone()))))
+            .collect()
+    }
+}
+
+async fn run_job(db: PgPool, job: Job, shutdown: ShutdownSignal) {
+    while let Some(next) = job.schedule.upcoming(Utc).next() {
+        let wait = (next - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+        if let Either::Right(_) = select(Box::pin(sleep(wait)), shutdown.clone()).await {
+            break;
+        }
+        if let Err(err) = run_once(&db, &job, next).await {
+            log::warn!("job {} could not run: {:?}", job.name, err);
+        }
+    }
+    log::info!("job {} stopped", job.name);
+}
+
+/// Runs the tick `scheduled_at` of a job unless another replica is running
+/// the job or already ran this tick.
+async fn run_once(db: &PgPool, job: &Job, scheduled_at: DateTime<Utc>) -> Result<(), ApiError> {
+    // held until this transaction ends, and released if the connection dies
+    let mut lock = db.begin().await?;
+    let locked = sqlx::query_scalar!(
+        r#"SELECT pg_try_advisory_xact_lock($1, hashtext($2)) AS "locked!""#,
+        LOCK_NAMESPACE,
+        job.name
+    )
+    .fetch_one(&mut *lock)
+    .await?;
+    if !locked {
+        log::debug!("job {} is running on another replica", job.name);
+        return Ok(());
+    }
+
+    // clocks of replicas differ, one may take the lock after another finished the tick
+    let run_id = sqlx::query_scalar!(
+        r#"INSERT INTO job_runs (job, scheduled_at) VALUES ($1, $2)
+        ON CONFLICT (job, scheduled_at) DO NOTHING RETURNING id"#,
+        job.name,
+        scheduled_at.naive_utc()
+    )
+    .fetch_optional(db)
+    .await?;
+    let Some(run_id) = run_id else {
+        return Ok(());
+    };
+
+    let started = Instant::now();
+    let result = (job.run)().await;
+    let duration_ms = started.elapsed().as_millis() as i64;
+    let (status, detail) = match result {
+        Ok(detail) => {
+            log::info!("job {} succeeded in {}ms: {}", job.name, duration_ms, detail);
+            ("succeeded", detail)
+        }
+        Err(err) => {
+            log::error!("job {} failed after {}ms: {:?}", job.name, duration_ms, err);
+            ("failed", format!("{:?}", err))
+        }
+    };
+
+    sqlx::query!(
+        "UPDATE job_runs SET status = $2, detail = $3, duration_ms = $4, finished_at = NOW() WHERE id = $1",
+        run_id,
+        status,
+        detail,
+        duration_ms
+    )
+    .execute(db)
+    .await?;
+    lock.commit().await?;
+    Ok(())
+}
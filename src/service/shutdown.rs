@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+use actix_web::rt::{signal, task::JoinHandle, time::timeout};
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+#[cfg(unix)]
+use futures::future::{select, Either};
+
+/// Resolves on Ctrl-C, or SIGTERM on unix, whichever comes first.
+pub async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use signal::unix::{signal as unix_signal, SignalKind};
+
+        match unix_signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                match select(Box::pin(signal::ctrl_c()), Box::pin(terminate.recv())).await {
+                    Either::Left(_) => "SIGINT",
+                    Either::Right(_) => "SIGTERM",
+                }
+            }
+            Err(err) => {
+                log::warn!("cannot listen for SIGTERM: {}", err);
+                let _ = signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+/// Resolves once the server has drained. Every background task gets a clone
+/// and finishes its current unit of work when it fires.
+pub type ShutdownSignal = Shared<oneshot::Receiver<()>>;
+
+/// Send on (or drop) the sender to stop every task holding the signal.
+pub fn shutdown_channel() -> (oneshot::Sender<()>, ShutdownSignal) {
+    let (sender, receiver) = oneshot::channel();
+    (sender, receiver.shared())
+}
+
+/// A spawned background task, named for the logs.
+pub type BackgroundTask = (String, JoinHandle<()>);
+
+/// Waits for every task until `limit` is up, then aborts those still running
+/// and returns their names.
+pub async fn join_within(tasks: Vec<BackgroundTask>, limit: Duration) -> Vec<String> {
+    let deadline = Instant::now() + limit;
+    let mut unfinished = vec![];
+    for (name, mut task) in tasks {
+        if timeout(deadline.saturating_duration_since(Instant::now()), &mut task).await.is_err() {
+            task.abort();
+            unfinished.push(name);
+        }
+    }
+    unfinished
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::{self, time::sleep};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn join_within_aborts_tasks_that_overrun() {
+        let tasks = vec![
+            ("quick".to_string(), rt::spawn(async {})),
+            ("hung".to_string(), rt::spawn(sleep(Duration::from_secs(60)))),
+        ];
+        let started = Instant::now();
+        assert_eq!(join_within(tasks, Duration::from_millis(50)).await, vec!["hung".to_string()]);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
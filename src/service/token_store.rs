@@ -0,0 +1,249 @@
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use super::redis::RedisConnection;
+use crate::utils::api_error::ApiError;
+use crate::modules::auth::auth_models::UserPayload;
+
+/// What is stored in Redis for every refresh token, keyed by the token hash.
+#[derive(Deserialize, Serialize)]
+pub struct RefreshSession {
+    pub user: UserPayload,
+    pub family: String,
+}
+
+pub enum RefreshOutcome {
+    /// The token was valid and is now spent, issue a new pair.
+    Rotated(RefreshSession),
+    /// The token was already spent, the whole family has been revoked.
+    Reused,
+    /// Unknown, expired or belongs to a revoked family.
+    Invalid,
+}
+
+fn session_key(token_hash: &str) -> String {
+    format!("refresh_token:{}", token_hash)
+}
+
+fn used_key(token_hash: &str) -> String {
+    format!("refresh_token_used:{}", token_hash)
+}
+
+fn family_key(family: &str) -> String {
+    format!("revoked_family:{}", family)
+}
+
+fn jti_key(jti: &str) -> String {
+    format!("revoked_jti:{}", jti)
+}
+
+pub async fn store_refresh_token(
+    conn: &RedisConnection,
+    token_hash: &str,
+    session: &RefreshSession,
+    ttl: i64,
+) -> Result<(), ApiError> {
+    let mut conn = conn.clone();
+    let value = serde_json::to_string(session)?;
+    conn.set_ex(session_key(token_hash), value, ttl as u64).await
+        .map_err(ApiError::from)
+}
+
+pub async fn find_refresh_token(conn: &RedisConnection, token_hash: &str) -> Result<Option<RefreshSession>, ApiError> {
+    let mut conn = conn.clone();
+    let value: Option<String> = conn.get(session_key(token_hash)).await?;
+    match value {
+        Some(value) => serde_json::from_str(&value).map(Some).map_err(ApiError::from),
+        None => Ok(None),
+    }
+}
+
+/// Spends a refresh token. Marking it as used is a single `SET NX` so two
+/// concurrent refreshes with the same token cannot both succeed.
+pub async fn consume_refresh_token(conn: &RedisConnection, token_hash: &str, ttl: i64) -> Result<RefreshOutcome, ApiError> {
+    let Some(session) = find_refresh_token(conn, token_hash).await? else {
+        return Ok(RefreshOutcome::Invalid);
+    };
+
+    let mut conn = conn.clone();
+    let first_use: Option<String> = redis::cmd("SET")
+        .arg(used_key(token_hash))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async(&mut conn)
+        .await?;
+
+    if first_use.is_none() {
+        revoke_family(&conn, &session.family, ttl).await?;
+        return Ok(RefreshOutcome::Reused);
+    }
+    if conn.exists(family_key(&session.family)).await? {
+        return Ok(RefreshOutcome::Invalid);
+    }
+    Ok(RefreshOutcome::Rotated(session))
+}
+
+/// Revokes every refresh and access token issued from `family`. The marker
+/// only has to outlive the longest lived token of the family.
+pub async fn revoke_family(conn: &RedisConnection, family: &str, ttl: i64) -> Result<(), ApiError> {
+    let mut conn = conn.clone();
+    conn.set_ex(family_key(family), 1, ttl as u64).await.map_err(ApiError::from)
+}
+
+pub async fn revoke_jti(conn: &RedisConnection, jti: &str, ttl: i64) -> Result<(), ApiError> {
+    if ttl <= 0 {
+        return Ok(());
+    }
+    let mut conn = conn.clone();
+    conn.set_ex(jti_key(jti), 1, ttl as u64).await.map_err(ApiError::from)
+}
+
+/// Whether an access token was revoked on its own or through its family.
+pub async fn is_revoked(conn: &RedisConnection, jti: &str, family: &str) -> Result<bool, ApiError> {
+    let mut conn = conn.clone();
+    let count: usize = redis::cmd("EXISTS")
+        .arg(jti_key(jti))
+        .arg(family_key(family))
+        .query_async(&mut conn)
+        .await?;
+    Ok(count > 0)
+}
+
+/// Deletes refresh sessions of revoked families, which can never be used
+/// again, and any token key that lost its expiry. Everything else expires
+/// on its own. Returns how many keys were deleted.
+pub async fn purge_stale(conn: &RedisConnection) -> Result<u64, ApiError> {
+    let mut conn = conn.clone();
+    let mut deleted = 0;
+    for pattern in ["refresh_token:*", "refresh_token_used:*", "revoked_family:*", "revoked_jti:*"] {
+        let mut keys: Vec<String> = vec![];
+        let mut scan = conn.scan_match::<_, String>(pattern).await?;
+        while let Some(key) = scan.next_item().await {
+            keys.push(key);
+        }
+        drop(scan);
+
+        for key in keys {
+            // -1: the key exists without an expiry
+            let ttl: i64 = conn.ttl(&key).await?;
+            let stale = ttl == -1 || (key.starts_with("refresh_token:") && family_revoked(&mut conn, &key).await?);
+            if stale {
+                let removed: u64 = conn.del(&key).await?;
+                deleted += removed;
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+async fn family_revoked(conn: &mut RedisConnection, session_key: &str) -> Result<bool, ApiError> {
+    let value: Option<String> = conn.get(session_key).await?;
+    let Some(session) = value.and_then(|value| serde_json::from_str::<RefreshSession>(&value).ok()) else {
+        return Ok(false);
+    };
+    Ok(conn.exists(family_key(&session.family)).await?)
+}
+
+/// These run against a real Redis, they are ignored unless asked for:
+/// `REDIS_HOSTNAME=localhost:6379 cargo test -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::config::settings::RedisSettings;
+    use crate::service::redis::redis_connect;
+
+    const TTL: i64 = 60;
+
+    async fn redis() -> RedisConnection {
+        let settings = RedisSettings {
+            hostname: std::env::var("REDIS_HOSTNAME").unwrap_or_else(|_| "localhost:6379".to_string()),
+            password: std::env::var("REDIS_PASSWORD").unwrap_or_default(),
+            connect_timeout_ms: 2000,
+            response_timeout_ms: 1000,
+            reconnect_retries: 1,
+        };
+        redis_connect(&settings).await.expect("redis is not reachable at REDIS_HOSTNAME")
+    }
+
+    /// A stored refresh token of a new family, keys are random so runs never collide.
+    async fn stored_token(conn: &RedisConnection, family: &str) -> String {
+        let token_hash = Uuid::new_v4().to_string();
+        let session = RefreshSession {
+            user: UserPayload {
+                id: Uuid::new_v4(),
+                email: "user@example.com".to_string(),
+                roles: vec!["user".to_string()],
+                permissions: vec![],
+            },
+            family: family.to_string(),
+        };
+        store_refresh_token(conn, &token_hash, &session, TTL).await.unwrap();
+        token_hash
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs redis"]
+    async fn refresh_token_rotates_once() {
+        let conn = redis().await;
+        let family = Uuid::new_v4().to_string();
+        let token_hash = stored_token(&conn, &family).await;
+
+        match consume_refresh_token(&conn, &token_hash, TTL).await.unwrap() {
+            RefreshOutcome::Rotated(session) => assert_eq!(session.family, family),
+            _ => panic!("first use must rotate"),
+        }
+        assert!(!is_revoked(&conn, &Uuid::new_v4().to_string(), &family).await.unwrap());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs redis"]
+    async fn reused_refresh_token_revokes_its_family() {
+        let conn = redis().await;
+        let family = Uuid::new_v4().to_string();
+        let spent = stored_token(&conn, &family).await;
+        let sibling = stored_token(&conn, &family).await;
+
+        assert!(matches!(consume_refresh_token(&conn, &spent, TTL).await.unwrap(), RefreshOutcome::Rotated(_)));
+        assert!(matches!(consume_refresh_token(&conn, &spent, TTL).await.unwrap(), RefreshOutcome::Reused));
+
+        // every access token and unspent refresh token of the family is dead
+        assert!(is_revoked(&conn, &Uuid::new_v4().to_string(), &family).await.unwrap());
+        assert!(matches!(consume_refresh_token(&conn, &sibling, TTL).await.unwrap(), RefreshOutcome::Invalid));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs redis"]
+    async fn unknown_refresh_token_is_invalid() {
+        let conn = redis().await;
+        let outcome = consume_refresh_token(&conn, &Uuid::new_v4().to_string(), TTL).await.unwrap();
+        assert!(matches!(outcome, RefreshOutcome::Invalid));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs redis"]
+    async fn revoked_jti_or_family_revokes_the_access_token() {
+        let conn = redis().await;
+        let (jti, family) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        assert!(!is_revoked(&conn, &jti, &family).await.unwrap());
+
+        revoke_jti(&conn, &jti, TTL).await.unwrap();
+        assert!(is_revoked(&conn, &jti, &Uuid::new_v4().to_string()).await.unwrap());
+
+        let other_jti = Uuid::new_v4().to_string();
+        revoke_family(&conn, &family, TTL).await.unwrap();
+        assert!(is_revoked(&conn, &other_jti, &family).await.unwrap());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs redis"]
+    async fn expired_jti_is_not_recorded() {
+        let conn = redis().await;
+        let jti = Uuid::new_v4().to_string();
+        revoke_jti(&conn, &jti, 0).await.unwrap();
+        assert!(!is_revoked(&conn, &jti, &Uuid::new_v4().to_string()).await.unwrap());
+    }
+}
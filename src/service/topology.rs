@@ -0,0 +1,216 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lapin::{
+    options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
+    protocol::{AMQPErrorKind, AMQPSoftError},
+    types::{AMQPValue, FieldTable},
+    Channel, ExchangeKind,
+};
+
+use super::rabbitmq::RabbitMqPool;
+use crate::config::settings::{BindingSettings, ExchangeSettings, ExchangeType, QueueSettings, Settings};
+use crate::utils::api_error::ApiError;
+
+/// Every exchange, queue and binding the app relies on: the events and dead
+/// letter exchanges, the queues of registered consumers and whatever the
+/// config file adds. Declared once per process, before the first publish or
+/// consume.
+#[derive(Debug, Default)]
+pub struct Topology {
+    exchanges: Vec<ExchangeSettings>,
+    queues: Vec<QueueSettings>,
+    bindings: Vec<BindingSettings>,
+    declared: AtomicBool,
+}
+
+#[derive(Debug)]
+pub enum TopologyError {
+    /// The broker refused a declaration, e.g. a queue exists with other
+    /// arguments. Retrying will not help, the config or broker must change.
+    Rejected(String),
+    /// The broker could not be reached, declaring again later may work.
+    Unavailable(String),
+}
+
+impl fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopologyError::Rejected(message) => write!(f, "rabbitmq topology rejected: {}", message),
+            TopologyError::Unavailable(message) => write!(f, "rabbitmq is unavailable: {}", message),
+        }
+    }
+}
+
+impl From<TopologyError> for ApiError {
+    fn from(err: TopologyError) -> Self {
+        ApiError::RabbitMq(err.to_string())
+    }
+}
+
+impl Topology {
+    pub fn new(settings: &Settings) -> Result<Topology, TopologyError> {
+        let mut topology = Topology::default();
+        for exchange in [
+            ExchangeSettings { name: settings.rabbitmq.exchange.clone(), kind: ExchangeType::Topic, durable: true },
+            ExchangeSettings {
+                name: settings.consumer.dead_letter_exchange.clone(),
+                kind: ExchangeType::Direct,
+                durable: true,
+            },
+        ] {
+            topology.exchange(exchange)?;
+        }
+
+        let configured = &settings.rabbitmq.topology;
+        for exchange in &configured.exchanges {
+            topology.exchange(exchange.clone())?;
+        }
+        for queue in &configured.queues {
+            topology.queue(queue.clone())?;
+        }
+        for binding in &configured.bindings {
+            topology.bind(&binding.queue, &binding.exchange, &binding.routing_key);
+        }
+        Ok(topology)
+    }
+
+    /// Adds an exchange. Adding the same one twice is a no-op, adding one
+    /// with the name of another is an error.
+    pub fn exchange(&mut self, exchange: ExchangeSettings) -> Result<(), TopologyError> {
+        match self.exchanges.iter().find(|known| known.name == exchange.name) {
+            Some(known) if *known == exchange => Ok(()),
+            Some(_) => Err(TopologyError::Rejected(format!("exchange {} is declared twice, differently", exchange.name))),
+            None => {
+                self.exchanges.push(exchange);
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds a queue, same rules as `exchange`.
+    pub fn queue(&mut self, queue: QueueSettings) -> Result<(), TopologyError> {
+        match self.queues.iter().find(|known| known.name == queue.name) {
+            Some(known) if *known == queue => Ok(()),
+            Some(_) => Err(TopologyError::Rejected(format!("queue {} is declared twice, differently", queue.name))),
+            None => {
+                self.queues.push(queue);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn bind(&mut self, queue: &str, exchange: &str, routing_key: &str) {
+        let binding = BindingSettings {
+            queue: queue.to_string(),
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+        };
+        if !self.bindings.contains(&binding) {
+            self.bindings.push(binding);
+        }
+    }
+
+    /// Declares everything unless this process already did. Declaring is
+    /// idempotent, so every replica does it and concurrent calls are fine.
+    pub async fn ensure(&self, rabbit: &RabbitMqPool) -> Result<(), TopologyError> {
+        if self.declared.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let conn = rabbit.get().await.map_err(|e| TopologyError::Unavailable(e.to_string()))?;
+        // a refused declaration closes the channel, keep that off the channels doing work
+        let channel = conn.create_channel().await.map_err(|e| TopologyError::Unavailable(e.to_string()))?;
+        self.declare(&channel).await?;
+        let _ = channel.close(200, "topology declared").await;
+
+        self.declared.store(true, Ordering::Release);
+        log::info!(
+            "rabbitmq topology declared: {} exchange(s), {} queue(s), {} binding(s)",
+            self.exchanges.len(),
+            self.queues.len(),
+            self.bindings.len(),
+        );
+        Ok(())
+    }
+
+    async fn declare(&self, channel: &Channel) -> Result<(), TopologyError> {
+        for exchange in &self.exchanges {
+            let options = ExchangeDeclareOptions { durable: exchange.durable, ..ExchangeDeclareOptions::default() };
+            channel
+                .exchange_declare(&exchange.name, exchange_kind(exchange.kind), options, FieldTable::default())
+                .await
+                .map_err(|e| declare_error(format!("exchange {}", exchange.name), e))?;
+        }
+        for queue in &self.queues {
+            let options = QueueDeclareOptions { durable: queue.durable, ..QueueDeclareOptions::default() };
+            channel
+                .queue_declare(&queue.name, options, queue_arguments(queue))
+                .await
+                .map_err(|e| declare_error(format!("queue {}", queue.name), e))?;
+        }
+        for binding in &self.bindings {
+            channel
+                .queue_bind(
+                    &binding.queue,
+                    &binding.exchange,
+                    &binding.routing_key,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await
+                .map_err(|e| {
+                    declare_error(format!("binding {} -> {} ({})", binding.exchange, binding.queue, binding.routing_key), e)
+                })?;
+        }
+        Ok(())
+    }
+}
+
+fn exchange_kind(kind: ExchangeType) -> ExchangeKind {
+    match kind {
+        ExchangeType::Direct => ExchangeKind::Direct,
+        ExchangeType::Fanout => ExchangeKind::Fanout,
+        ExchangeType::Topic => ExchangeKind::Topic,
+        ExchangeType::Headers => ExchangeKind::Headers,
+    }
+}
+
+fn queue_arguments(queue: &QueueSettings) -> FieldTable {
+    let mut arguments = FieldTable::default();
+    if queue.quorum {
+        arguments.insert("x-queue-type".into(), AMQPValue::LongString("quorum".into()));
+    }
+    if let Some(ttl) = queue.message_ttl_ms {
+        arguments.insert("x-message-ttl".into(), AMQPValue::LongUInt(ttl));
+    }
+    if let Some(max_length) = queue.max_length {
+        arguments.insert("x-max-length".into(), AMQPValue::LongUInt(max_length));
+    }
+    if let Some(exchange) = &queue.dead_letter_exchange {
+        arguments.insert("x-dead-letter-exchange".into(), AMQPValue::LongString(exchange.clone().into()));
+    }
+    if let Some(routing_key) = &queue.dead_letter_routing_key {
+        arguments.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString(routing_key.clone().into()));
+    }
+    if let Some(limit) = queue.delivery_limit {
+        arguments.insert("x-delivery-limit".into(), AMQPValue::LongUInt(limit));
+    }
+    arguments
+}
+
+fn declare_error(what: String, err: lapin::Error) -> TopologyError {
+    match &err {
+        lapin::Error::ProtocolError(amqp) => match amqp.kind() {
+            AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED) => TopologyError::Rejected(format!(
+                "{} already exists with other arguments, delete it or match the configuration ({})",
+                what,
+                amqp.get_message(),
+            )),
+            AMQPErrorKind::Soft(AMQPSoftError::NOTFOUND) => {
+                TopologyError::Rejected(format!("{} refers to something missing ({})", what, amqp.get_message()))
+            }
+            _ => TopologyError::Unavailable(format!("{}: {}", what, err)),
+        },
+        _ => TopologyError::Unavailable(format!("{}: {}", what, err)),
+    }
+}
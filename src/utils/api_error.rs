@@ -0,0 +1,266 @@
+use actix_web::{
+    error::{JsonPayloadError, QueryPayloadError},
+    http::StatusCode,
+    HttpResponse, ResponseError,
+};
+use serde_json::{json, Value};
+use std::fmt;
+use validator::ValidationErrors;
+
+use crate::midleware::authmiddlewares::UnauthorizedError;
+use crate::utils::jwt::TokenError;
+
+/// Error returned by every handler. All variants render the same envelope:
+/// `{"status": "error", "code": "...", "message": "...", "details": ...}`.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    InvalidJson(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType,
+    Validation(ValidationErrors),
+    InvalidCredentials,
+    Unauthorized(UnauthorizedError),
+    Forbidden(String),
+    MissingPermission(&'static str),
+    NotFound(String),
+    Conflict(String),
+    Database(sqlx::Error),
+    Redis(String),
+    RabbitMq(String),
+    Kafka(String),
+    Jwt(String),
+    Internal(String),
+}
+
+impl ApiError {
+    /// Replaces the message of a conflict with one the client can act on.
+    pub fn on_conflict(self, message: &str) -> ApiError {
+        match self {
+            ApiError::Conflict(_) => ApiError::Conflict(message.to_string()),
+            other => other,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::InvalidJson(_) => "invalid_json",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType => "unsupported_media_type",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::Unauthorized(reason) => reason.code(),
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::MissingPermission(_) => "missing_permission",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Database(sqlx::Error::PoolTimedOut) => "database_unavailable",
+            ApiError::Database(_) => "database_error",
+            ApiError::Redis(_) => "cache_unavailable",
+            ApiError::RabbitMq(_) | ApiError::Kafka(_) => "broker_unavailable",
+            ApiError::Jwt(_) => "token_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// The underlying failure, only ever written to the logs.
+    fn cause(&self) -> String {
+        match self {
+            ApiError::Database(err) => err.to_string(),
+            ApiError::Redis(cause)
+            | ApiError::RabbitMq(cause)
+            | ApiError::Kafka(cause)
+            | ApiError::Jwt(cause)
+            | ApiError::Internal(cause) => cause.clone(),
+            other => other.to_string(),
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            ApiError::Validation(errors) => Some(validation_details(errors)),
+            ApiError::MissingPermission(permission) => Some(json!({ "permission": permission })),
+            _ => None,
+        }
+    }
+}
+
+/// `{"field": ["message", ...]}` for every failed field.
+pub fn validation_details(errors: &ValidationErrors) -> Value {
+    let fields = errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let messages: Vec<String> = errors
+                .iter()
+                .map(|error| match &error.message {
+                    Some(message) => message.to_string(),
+                    None => error.code.to_string(),
+                })
+                .collect();
+            (field.to_string(), json!(messages))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    Value::Object(fields)
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::InvalidJson(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message) => write!(f, "{}", message),
+            ApiError::UnsupportedMediaType => write!(f, "request body must be application/json"),
+            ApiError::Validation(_) => write!(f, "request body is invalid"),
+            ApiError::InvalidCredentials => write!(f, "email or password is wrong"),
+            ApiError::Unauthorized(reason) => write!(f, "{}", reason),
+            ApiError::MissingPermission(permission) => write!(f, "missing permission {}", permission),
+            ApiError::Database(sqlx::Error::PoolTimedOut) => write!(f, "database is unavailable"),
+            ApiError::Database(_) => write!(f, "something bad happened while talking to the database"),
+            ApiError::Redis(_) => write!(f, "cache is unavailable"),
+            ApiError::RabbitMq(_) | ApiError::Kafka(_) => write!(f, "message broker is unavailable"),
+            ApiError::Jwt(_) => write!(f, "token could not be issued"),
+            ApiError::Internal(_) => write!(f, "something bad happened"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::InvalidJson(_) => StatusCode::BAD_REQUEST,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ApiError::Unauthorized(UnauthorizedError::Unavailable) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) | ApiError::MissingPermission(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Database(sqlx::Error::PoolTimedOut) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Redis(_) | ApiError::RabbitMq(_) | ApiError::Kafka(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Database(_) | ApiError::Jwt(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        // the client only sees the generic message, keep the cause in the logs
+        if status.is_server_error() {
+            log::error!("{}: {}", self.code(), self.cause());
+        }
+
+        let mut body = json!({
+            "status": "error",
+            "code": self.code(),
+            "message": self.to_string(),
+        });
+        if let Some(details) = self.details() {
+            body["details"] = details;
+        }
+        HttpResponse::build(status).json(body)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        match &err {
+            sqlx::Error::RowNotFound => ApiError::NotFound("data not found".to_string()),
+            sqlx::Error::Database(db_err) => match db_err.code().as_deref() {
+                // unique_violation
+                Some("23505") => ApiError::Conflict("data already exists".to_string()),
+                // foreign_key_violation
+                Some("23503") => ApiError::Conflict("data is referenced by or references missing data".to_string()),
+                // not_null_violation, check_violation, string_data_right_truncation
+                // the message names columns and types, keep it in the logs
+                Some("23502") | Some("23514") | Some("22001") => {
+                    log::warn!("rejected by the database: {}", db_err.message());
+                    ApiError::BadRequest("data is missing, too long or out of range".to_string())
+                }
+                _ => ApiError::Database(err),
+            },
+            _ => ApiError::Database(err),
+        }
+    }
+}
+
+/// Used as the `JsonConfig` error handler so malformed bodies get the same
+/// envelope as every other error.
+impl From<JsonPayloadError> for ApiError {
+    fn from(err: JsonPayloadError) -> Self {
+        match err {
+            JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+                ApiError::PayloadTooLarge(err.to_string())
+            }
+            JsonPayloadError::ContentType => ApiError::UnsupportedMediaType,
+            JsonPayloadError::Deserialize(err) => ApiError::InvalidJson(err.to_string()),
+            JsonPayloadError::Serialize(err) => ApiError::Internal(err.to_string()),
+            other => ApiError::BadRequest(other.to_string()),
+        }
+    }
+}
+
+impl From<QueryPayloadError> for ApiError {
+    fn from(err: QueryPayloadError) -> Self {
+        ApiError::BadRequest(err.to_string())
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> Self {
+        ApiError::Validation(errors)
+    }
+}
+
+impl From<UnauthorizedError> for ApiError {
+    fn from(reason: UnauthorizedError) -> Self {
+        ApiError::Unauthorized(reason)
+    }
+}
+
+impl From<TokenError> for ApiError {
+    fn from(err: TokenError) -> Self {
+        ApiError::Unauthorized(err.into())
+    }
+}
+
+impl From<redis::RedisError> for ApiError {
+    fn from(err: redis::RedisError) -> Self {
+        ApiError::Redis(err.to_string())
+    }
+}
+
+impl From<deadpool_lapin::PoolError> for ApiError {
+    fn from(err: deadpool_lapin::PoolError) -> Self {
+        ApiError::RabbitMq(err.to_string())
+    }
+}
+
+impl From<lapin::Error> for ApiError {
+    fn from(err: lapin::Error) -> Self {
+        ApiError::RabbitMq(err.to_string())
+    }
+}
+
+impl From<kafka::Error> for ApiError {
+    fn from(err: kafka::Error) -> Self {
+        ApiError::Kafka(err.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for ApiError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        ApiError::Jwt(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(err: serde_json::Error) -> Self {
+        ApiError::Internal(err.to_string())
+    }
+}
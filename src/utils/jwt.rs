@@ -1,5 +1,9 @@
-use jsonwebtoken::{encode, EncodingKey, Header, TokenData,decode,DecodingKey,Validation ,errors::Error as JwtError};
+use std::collections::HashMap;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use jsonwebtoken::{encode, EncodingKey, Header, TokenData,decode,decode_header,DecodingKey,Validation,Algorithm ,errors::{Error as JwtError, ErrorKind}};
 
+use crate::config::settings::JwtSettings;
 use crate::modules::auth::auth_models::UserPayload;
 use serde::{Deserialize, Serialize};
 
@@ -7,6 +11,8 @@ use serde::{Deserialize, Serialize};
 pub struct TokenClaims {
     pub iat : i64,
     pub exp : i64,
+    pub jti : String,
+    pub sid : String,
     pub user :UserPayload,
 }
 
@@ -14,31 +20,215 @@ pub struct TokenClaims {
 pub struct JwtUserToken{
         pub user: UserPayload,
         pub iat: i64,    
-        pub exp: i64
+        pub exp: i64,
+        pub jti: String,
+        pub sid: String
+}
+
+/// Signing key plus every key still accepted for verification, indexed by `kid`.
+#[derive(Clone)]
+pub struct JwtKeys {
+    pub algorithm: Algorithm,
+    pub key_id: String,
+    pub max_age: i64,
+    encoding: EncodingKey,
+    decoding: HashMap<String, DecodingKey>,
+}
+
+impl JwtKeys {
+    pub fn new(settings:&JwtSettings) -> Result<JwtKeys, String> {
+        let encoding = match settings.algorithm {
+            Algorithm::HS256 => EncodingKey::from_secret(settings.secret.as_bytes()),
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(settings.private_key.as_bytes())
+                .map_err(|e| format!("invalid RS256 private key: {}", e))?,
+            Algorithm::ES256 => EncodingKey::from_ec_pem(settings.private_key.as_bytes())
+                .map_err(|e| format!("invalid ES256 private key: {}", e))?,
+            other => return Err(format!("unsupported jwt algorithm {:?}", other)),
+        };
+
+        let mut decoding = HashMap::new();
+        let current = match settings.algorithm {
+            Algorithm::HS256 => settings.secret.as_str(),
+            _ => settings.public_key.as_str(),
+        };
+        decoding.insert(settings.key_id.clone(), decoding_key(settings.algorithm, current)?);
+        for (kid, key) in &settings.previous_keys {
+            let key = decoding_key(settings.algorithm, key).map_err(|e| format!("key {}: {}", kid, e))?;
+            decoding.insert(kid.clone(), key);
+        }
+
+        Ok(JwtKeys {
+            algorithm: settings.algorithm,
+            key_id: settings.key_id.clone(),
+            max_age: settings.max_age,
+            encoding,
+            decoding,
+        })
+    }
+}
+
+fn decoding_key(algorithm:Algorithm, key:&str) -> Result<DecodingKey, String> {
+    match algorithm {
+        Algorithm::HS256 => Ok(DecodingKey::from_secret(key.as_bytes())),
+        Algorithm::RS256 => DecodingKey::from_rsa_pem(key.as_bytes())
+            .map_err(|e| format!("invalid RS256 public key: {}", e)),
+        Algorithm::ES256 => DecodingKey::from_ec_pem(key.as_bytes())
+            .map_err(|e| format!("invalid ES256 public key: {}", e)),
+        other => Err(format!("unsupported jwt algorithm {:?}", other)),
+    }
 }
 
 impl TokenClaims {
-    pub fn generate_token(data:UserPayload) -> Result<String, String> {
-        let max_age:i64 = 60 * 60 * 24;
+    /// Signs a short-lived access token. `sid` is the refresh token family the
+    /// access token was issued from, so revoking the family revokes it too.
+    pub fn generate_token(data:UserPayload, sid:&str, keys:&JwtKeys) -> Result<String, JwtError> {
         let iat = chrono::Utc::now().timestamp();
-        let exp = iat + max_age;
+        let exp = iat + keys.max_age;
         let token = TokenClaims {
             iat,
             exp,
+            jti: uuid::Uuid::new_v4().to_string(),
+            sid: sid.to_string(),
             user: data,
         };
 
-        let jwt_secret:EncodingKey = EncodingKey::from_secret("secret_key".as_bytes());
-        let token = encode( &Header::default(),&token, &jwt_secret).unwrap();
-        Ok(token)
+        let mut header = Header::new(keys.algorithm);
+        header.kid = Some(keys.key_id.clone());
+        encode(&header, &token, &keys.encoding)
     }
 }
 
-pub fn decode_token(token: String) -> Result<TokenData<JwtUserToken>, String> {
-    let user = decode::<JwtUserToken>(
+/// Opaque refresh token handed to the client, only its hash is stored.
+pub fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hash_refresh_token(token:&str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Why a bearer token was rejected.
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    Expired,
+    InvalidSignature,
+    Malformed,
+}
+
+impl From<JwtError> for TokenError {
+    fn from(err: JwtError) -> Self {
+        match err.kind() {
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm => TokenError::InvalidSignature,
+            _ => TokenError::Malformed,
+        }
+    }
+}
+
+pub fn decode_token(token: String, keys:&JwtKeys) -> Result<TokenData<JwtUserToken>, TokenError> {
+    let header = decode_header(&token)?;
+    // tokens issued before `kid` was set are checked against the active key
+    let kid = header.kid.unwrap_or_else(|| keys.key_id.clone());
+    // a key we never had (or already dropped) cannot have signed this token
+    let key = keys.decoding.get(&kid).ok_or(TokenError::InvalidSignature)?;
+
+    Ok(decode::<JwtUserToken>(
         &token,
-        &DecodingKey::from_secret("secret_key".as_bytes()),
-        &Validation::default(),
-    ).map_err(|e: JwtError| e.to_string());
-    user
-}
\ No newline at end of file
+        key,
+        &Validation::new(keys.algorithm),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::errors::ErrorKind;
+
+    use super::*;
+
+    fn settings(key_id: &str, secret: &str, previous_keys: &[(&str, &str)]) -> JwtSettings {
+        JwtSettings {
+            algorithm: Algorithm::HS256,
+            key_id: key_id.to_string(),
+            secret: secret.to_string(),
+            private_key: String::new(),
+            public_key: String::new(),
+            previous_keys: previous_keys.iter().map(|(kid, key)| (kid.to_string(), key.to_string())).collect(),
+            max_age: 60,
+            refresh_max_age: 3600,
+        }
+    }
+
+    fn user() -> UserPayload {
+        UserPayload {
+            id: uuid::Uuid::new_v4(),
+            email: "user@example.com".to_string(),
+            roles: vec!["user".to_string()],
+            permissions: vec![],
+        }
+    }
+
+    const OLD_SECRET: &str = "old-secret-old-secret-old-secret";
+    const NEW_SECRET: &str = "new-secret-new-secret-new-secret";
+
+    fn rotated_keys() -> JwtKeys {
+        JwtKeys::new(&settings("new", NEW_SECRET, &[("old", OLD_SECRET)])).unwrap()
+    }
+
+    /// Signs claims with `secret`, claiming `kid` in the header.
+    fn sign(kid: Option<&str>, secret: &str, exp_offset: i64) -> String {
+        let iat = chrono::Utc::now().timestamp();
+        let claims = TokenClaims {
+            iat,
+            exp: iat + exp_offset,
+            jti: "jti".to_string(),
+            sid: "family".to_string(),
+            user: user(),
+        };
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = kid.map(str::to_string);
+        encode(&header, &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn token_errors_map_to_their_reason() {
+        assert_eq!(TokenError::from(JwtError::from(ErrorKind::ExpiredSignature)), TokenError::Expired);
+        assert_eq!(TokenError::from(JwtError::from(ErrorKind::InvalidSignature)), TokenError::InvalidSignature);
+        assert_eq!(TokenError::from(JwtError::from(ErrorKind::InvalidAlgorithm)), TokenError::InvalidSignature);
+        assert_eq!(TokenError::from(JwtError::from(ErrorKind::InvalidToken)), TokenError::Malformed);
+        assert_eq!(TokenError::from(JwtError::from(ErrorKind::ImmatureSignature)), TokenError::Malformed);
+    }
+
+    #[test]
+    fn issued_token_decodes() {
+        let keys = rotated_keys();
+        let token = TokenClaims::generate_token(user(), "family", &keys).unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("new"));
+        assert_eq!(decode_token(token, &keys).unwrap().claims.sid, "family");
+    }
+
+    #[test]
+    fn token_of_a_previous_key_still_decodes() {
+        assert!(decode_token(sign(Some("old"), OLD_SECRET, 60), &rotated_keys()).is_ok());
+    }
+
+    #[test]
+    fn token_without_kid_is_checked_against_the_active_key() {
+        assert!(decode_token(sign(None, NEW_SECRET, 60), &rotated_keys()).is_ok());
+        assert_eq!(decode_token(sign(None, OLD_SECRET, 60), &rotated_keys()).err(), Some(TokenError::InvalidSignature));
+    }
+
+    #[test]
+    fn unknown_or_mismatched_kid_is_rejected() {
+        assert_eq!(decode_token(sign(Some("gone"), OLD_SECRET, 60), &rotated_keys()).err(), Some(TokenError::InvalidSignature));
+        // signed with the old key while claiming the new one
+        assert_eq!(decode_token(sign(Some("new"), OLD_SECRET, 60), &rotated_keys()).err(), Some(TokenError::InvalidSignature));
+    }
+
+    #[test]
+    fn expired_and_garbage_tokens_are_rejected() {
+        assert_eq!(decode_token(sign(Some("new"), NEW_SECRET, -120), &rotated_keys()).err(), Some(TokenError::Expired));
+        assert_eq!(decode_token("not-a-jwt".to_string(), &rotated_keys()).err(), Some(TokenError::Malformed));
+    }
+}
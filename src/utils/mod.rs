@@ -1 +1,3 @@
-// pub mod jwt;
\ No newline at end of file
+pub mod jwt;
+pub mod api_error;
+pub mod validated_json;
\ No newline at end of file
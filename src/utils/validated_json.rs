@@ -0,0 +1,44 @@
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+use validator::Validate;
+
+use crate::utils::api_error::ApiError;
+
+/// A JSON body that passed its `validator` rules. Invalid bodies never reach
+/// the handler, they are answered with 422 and the failing fields.
+pub struct ValidatedJson<T>(pub T);
+
+impl<T> ValidatedJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for ValidatedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        // parse errors go through the JsonConfig error handler
+        let json = web::Json::<T>::from_request(req, payload);
+
+        Box::pin(async move {
+            let body = json.await?.into_inner();
+            body.validate().map_err(ApiError::from)?;
+            Ok(ValidatedJson(body))
+        })
+    }
+}